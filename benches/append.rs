@@ -0,0 +1,152 @@
+// Append throughput, over the in-memory and TCP transports, with pushes one at a time
+// and with the default push window.
+//
+// Over localhost, calls are bound by CPU rather than the network, so the in-memory
+// transport is also run with a delay on every call, as a stand-in for network latency.
+//
+//   cargo bench --bench append
+
+use gfs::master::{MasterServer, MasterServerState};
+use gfs::client::{Client, DEFAULT_PUSH_WINDOW};
+use gfs::chunk::CHUNK_SIZE_BYTES;
+use gfs::chunk::ChunkHash;
+use gfs::chunkserver::{Chunkserver, ChunkserverError, ChunkserverStorage};
+use gfs::common::{ChunkserverHandle, MasterHandle, NetworkShim, Transport};
+use gfs::rpc::{serve_chunkserver, serve_master, RemoteMaster, TcpTransport};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const N_CHUNKSERVERS: usize = 4;
+const CHUNKS_PER_APPEND: usize = 64;
+const APPENDS: usize = 64;
+/// The delay on each call to a chunkserver, for the in-memory transport with latency.
+const LATENCY: Duration = Duration::from_millis(1);
+
+/// A transport which delays every call to a chunkserver.
+struct SlowTransport(Arc<Mutex<NetworkShim>>);
+
+struct SlowChunkserver(Arc<dyn ChunkserverHandle>);
+
+impl Transport for SlowTransport {
+    fn get_node(&self, id: &str) -> Option<Arc<dyn ChunkserverHandle>> {
+        let chunkserver = self.0.lock().unwrap().get_node(id)?;
+        Some(Arc::new(SlowChunkserver(chunkserver)))
+    }
+}
+
+impl ChunkserverHandle for SlowChunkserver {
+    fn push_chunk(&self, data: &[u8]) -> Result<(), ChunkserverError> {
+        std::thread::sleep(LATENCY);
+        self.0.push_chunk(data)
+    }
+
+    fn commit_chunk(&self, chunk_hash: ChunkHash, chunk_id: u64, version: u64) -> Result<(), ChunkserverError> {
+        std::thread::sleep(LATENCY);
+        self.0.commit_chunk(chunk_hash, chunk_id, version)
+    }
+
+    fn read_chunk(&self, chunk_id: u64) -> Result<Vec<u8>, ChunkserverError> {
+        std::thread::sleep(LATENCY);
+        self.0.read_chunk(chunk_id)
+    }
+
+    fn replicate_chunk(&self, chunk_id: u64, version: u64, source: &str) -> Result<(), ChunkserverError> {
+        std::thread::sleep(LATENCY);
+        self.0.replicate_chunk(chunk_id, version, source)
+    }
+
+    fn update_chunk_version(&self, chunk_id: u64, version: u64) -> Result<(), ChunkserverError> {
+        std::thread::sleep(LATENCY);
+        self.0.update_chunk_version(chunk_id, version)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Network {
+    Memory,
+    SlowMemory,
+    Tcp,
+}
+
+/// A cluster of a master and chunkservers in this process, and a client of it.
+struct Cluster {
+    client: Client,
+    network: Arc<Mutex<dyn Transport>>,
+    storage_dir: PathBuf,
+}
+
+fn free_addr() -> String {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+}
+
+fn start_cluster(name: &str, kind: Network) -> Cluster {
+    let storage_dir = std::env::temp_dir().join(format!("gfs-bench-{name}-{}", std::process::id()));
+    let shim = Arc::new(Mutex::new(NetworkShim::new()));
+    let tcp = kind == Network::Tcp;
+    let network: Arc<Mutex<dyn Transport>> = match kind {
+        Network::Memory => shim.clone(),
+        Network::SlowMemory => Arc::new(Mutex::new(SlowTransport(shim.clone()))),
+        Network::Tcp => Arc::new(Mutex::new(TcpTransport::new())),
+    };
+
+    let master = Arc::new(Mutex::new(MasterServer::new(network.clone(), MasterServerState::new())));
+    let master_handle: Arc<dyn MasterHandle> = if tcp {
+        let addr = free_addr();
+        serve_master(master.clone(), &addr).unwrap();
+        Arc::new(RemoteMaster::new(&addr))
+    } else {
+        master.clone()
+    };
+
+    for i in 0..N_CHUNKSERVERS {
+        let storage = Box::new(ChunkserverStorage::new(storage_dir.join(format!("chunkserver-{i}"))));
+        let id = if tcp { free_addr() } else { format!("chunkserver-{i}") };
+        let chunkserver = Arc::new(Mutex::new(Chunkserver::new(master_handle.clone(), network.clone(), id.clone(), u64::MAX, storage)));
+        if tcp {
+            serve_chunkserver(chunkserver.clone(), &id).unwrap();
+        } else {
+            shim.lock().unwrap().add_node(chunkserver.clone());
+        }
+        std::thread::spawn(move || Chunkserver::run(chunkserver));
+    }
+
+    // Wait for every chunkserver to register.
+    while master.lock().unwrap().get_free_chunkservers(1, 3).len() < N_CHUNKSERVERS {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    Cluster { client: Client::new(master_handle), network, storage_dir }
+}
+
+/// Append `appends` times, returning the throughput in MiB/s.
+fn run(cluster: &mut Cluster, push_window: usize, appends: usize) -> f64 {
+    cluster.client.set_push_window(push_window);
+    // Every chunk of an append differs, as a repeated chunk is placed with its first copy.
+    let data: Vec<u8> = (0..CHUNKS_PER_APPEND * CHUNK_SIZE_BYTES).map(|x| (x + x / CHUNK_SIZE_BYTES) as u8).collect();
+    let start = Instant::now();
+    for _ in 0..appends {
+        cluster.client.append(&format!("/bench-{push_window}"), &data, cluster.network.clone()).unwrap();
+    }
+    (appends * data.len()) as f64 / (1024.0 * 1024.0) / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let mut results = vec![];
+    for (name, kind) in [("memory", Network::Memory), ("memory+1ms", Network::SlowMemory), ("tcp", Network::Tcp)] {
+        let mut cluster = start_cluster(name, kind);
+        // Warm up.
+        run(&mut cluster, 0, APPENDS / 8);
+        for push_window in [1, DEFAULT_PUSH_WINDOW] {
+            results.push((name, push_window, run(&mut cluster, push_window, APPENDS)));
+        }
+        std::fs::remove_dir_all(&cluster.storage_dir).unwrap();
+    }
+
+    println!();
+    println!("{N_CHUNKSERVERS} chunkservers, {APPENDS} appends of {CHUNKS_PER_APPEND} chunks");
+    for (name, push_window, throughput) in results {
+        println!("{name:>12} window={push_window:<3} {throughput:8.2} MiB/s");
+    }
+}
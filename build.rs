@@ -0,0 +1,9 @@
+fn main() {
+    // Generate Rust types for the wire schema. Uses the pure-Rust parser so protoc isn't needed.
+    protobuf_codegen::Codegen::new()
+        .pure()
+        .include("proto")
+        .input("proto/gfs.proto")
+        .cargo_out_dir("proto")
+        .run_from_script();
+}
@@ -1,9 +1,10 @@
-use gfs::master::MasterServer;
+use gfs::master::{spawn_checkpointer, MasterServer};
 use gfs::client::Client;
+use gfs::async_client::AsyncClient;
 use gfs::chunkserver::Chunkserver;
 use gfs::chunkserver::ChunkserverStorage;
-use gfs::common::NetworkShim;
-use gfs::master::MasterServerState;
+use gfs::common::{MasterHandle, NetworkShim, Transport};
+use gfs::rpc::{serve_chunkserver, serve_master, RemoteMaster, TcpTransport};
 use byte_unit::Byte;
 use std::sync::{Arc, Mutex};
 use std::path::PathBuf;
@@ -11,40 +12,43 @@ use std::path::PathBuf;
 
 #[tokio::main]
 async fn main() {
-    let network = Arc::new(Mutex::new(NetworkShim::new()));
+    // With --tcp, every node talks over localhost sockets instead of in-memory.
+    let tcp = std::env::args().any(|x| x == "--tcp");
+    let shim = Arc::new(Mutex::new(NetworkShim::new()));
+    let network: Arc<Mutex<dyn Transport>> = if tcp {
+        Arc::new(Mutex::new(TcpTransport::new()))
+    } else {
+        shim.clone()
+    };
 
     // Setup master.
     println!("Creating master.\n");
-    let master_state_path = PathBuf::from("./data/master/state");
-    let master_state: MasterServerState = {
-        if master_state_path.try_exists().unwrap_or(false) {
-            MasterServerState::from_file(master_state_path)
-        } else {
-            std::fs::create_dir_all(master_state_path.parent().unwrap()).unwrap();
-            let master_state = MasterServerState::new();
-            master_state.to_file(master_state_path);
-            master_state
-        }
-    };
-    let master = Arc::new(Mutex::new(MasterServer::new(network.clone(), master_state)));
+    let master = Arc::new(Mutex::new(MasterServer::open(network.clone(), PathBuf::from("./data/master")).unwrap()));
     let master_thread = master.clone();
+    spawn_checkpointer(master.clone(), std::time::Duration::from_secs(5));
 
     // Start master.
     std::thread::spawn(move || {
-        master_thread.lock().unwrap().run();
+        MasterServer::run(master_thread);
     });
+    let master_handle: Arc<dyn MasterHandle> = if tcp {
+        serve_master(master.clone(), "127.0.0.1:7000").unwrap();
+        Arc::new(RemoteMaster::new("127.0.0.1:7000"))
+    } else {
+        master.clone()
+    };
 
     // Setup client.
     println!("Creating client.\n");
-    let client: Client = Client::new(master.clone());
+    let client: Client = Client::new(master_handle.clone());
     
     // Get a directory listing.
-    println!("> ls /"); client.ls("/").iter().for_each(|x| println!("{}", x));
-    println!("> ls /files/"); client.ls("/files/").iter().for_each(|x| println!("{}", x));
+    println!("> ls /"); client.ls("/").unwrap().iter().for_each(|x| println!("{}", x));
+    println!("> ls /files/"); client.ls("/files/").unwrap().iter().for_each(|x| println!("{}", x));
 
     // Get disk usage.
-    println!("> df"); println!("disk free: {:#}", Byte::from_u64(client.df()));
-    println!("> du"); println!("disk used: {:#}", Byte::from_u64(client.du()));
+    println!("> df"); println!("disk free: {:#}", Byte::from_u64(client.df().unwrap()));
+    println!("> du"); println!("disk used: {:#}", Byte::from_u64(client.du().unwrap()));
 
     // Setup chunkserver 1-N.
     let n_chunkservers = 3;
@@ -78,17 +82,23 @@ async fn main() {
         println!("Creating chunkserver {}.\n", i);
         // data path is relative ./data/chunkserver-{i}
         let storage_dir = PathBuf::from(format!("./data/chunkserver-{i}"));
-        let storage = ChunkserverStorage::new(storage_dir);
-        let chunkserver = Arc::new(Mutex::new(Chunkserver::new(master.clone(), format!("chunkserver-{i}"), 1024, storage)));
+        let storage = Box::new(ChunkserverStorage::new(storage_dir));
+        // Over TCP, a chunkserver's ID is its listen address.
+        let id = if tcp { format!("127.0.0.1:{}", 7001 + i) } else { format!("chunkserver-{i}") };
+        let chunkserver = Arc::new(Mutex::new(Chunkserver::new(master_handle.clone(), network.clone(), id.clone(), 1024, storage)));
         let cs2 = chunkserver.clone();
 
+        // Register the chunkserver with the network.
+        if tcp {
+            serve_chunkserver(cs2, &id).unwrap();
+        } else {
+            shim.lock().unwrap().add_node(cs2);
+        }
+
         // Start the chunkserver.
         std::thread::spawn(move || {
-            chunkserver.lock().unwrap().run();
+            Chunkserver::run(chunkserver);
         });
-
-        // Register the chunkserver with the master.
-        network.clone().lock().unwrap().add_node(cs2);
     }
 
     // Wait for all chunkservers to start.
@@ -99,8 +109,8 @@ async fn main() {
     // });
 
     // Get disk usage.
-    println!("> df"); println!("disk free: {:#}", Byte::from_u64(client.df()));
-    println!("> du"); println!("disk used: {:#}", Byte::from_u64(client.du()));
+    println!("> df"); println!("disk free: {:#}", Byte::from_u64(client.df().unwrap()));
+    println!("> du"); println!("disk used: {:#}", Byte::from_u64(client.du().unwrap()));
 
     // Poll until master has 3 chunkservers free.
     while master.lock().unwrap().get_free_chunkservers(1, 3).len() < 3 {
@@ -108,16 +118,24 @@ async fn main() {
     }
 
     // Convert string to bytes.
-    client.append("/test", "hello world\n".as_bytes(), network.clone());
-    client.append("/test", "hello again".as_bytes(), network.clone());
-    client.append("/test", "dog".as_bytes(), network.clone());
+    client.append("/test", "hello world\n".as_bytes(), network.clone()).unwrap();
+    client.append("/test", "hello again".as_bytes(), network.clone()).unwrap();
+    client.append("/test", "dog".as_bytes(), network.clone()).unwrap();
 
-    println!("> ls /"); client.ls_tree("/").iter().for_each(|x| println!("{}", x));
-    println!("> df"); println!("disk free: {:#}", Byte::from_u64(client.df()));
-    println!("> du"); println!("disk used: {:#}", Byte::from_u64(client.du()));
+    println!("> ls /"); client.ls_tree("/").unwrap().iter().for_each(|x| println!("{}", x));
+    println!("> df"); println!("disk free: {:#}", Byte::from_u64(client.df().unwrap()));
+    println!("> du"); println!("disk used: {:#}", Byte::from_u64(client.du().unwrap()));
 
     // master_state.to_file(master_state_path);
-    println!("> cat /test"); println!("{:?}", client.read_full("/test", network));
+    println!("> cat /test"); println!("{:?}", client.read_full("/test", network.clone()).unwrap());
+
+    // The same, from async code. The async client only speaks TCP.
+    if tcp {
+        let async_client = AsyncClient::new("127.0.0.1:7000");
+        async_client.append("/async", "hello from tokio".as_bytes()).await.unwrap();
+        println!("> stat /async"); println!("{:?}", async_client.stat("/async").await.unwrap());
+        println!("> cat /async"); println!("{:?}", String::from_utf8_lossy(&async_client.read("/async", 0, u64::MAX).await.unwrap()));
+    }
 
     // client.read("/test", 0, 100, network.clone());
 
@@ -125,7 +143,7 @@ async fn main() {
     // First client calls master for set of chunkservers to store data.
     // Then client pushes data to chunkservers.
     // Then client calls master to create file and commit with data.
-    // Master will call each chunkserver and ask it to commit chunk from LRU.
+    // Master will call each chunkserver and ask it to commit the pushed chunk.
     // Then it will create the file entry with the chunk locations and commit.
 
 }
\ No newline at end of file
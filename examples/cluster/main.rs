@@ -0,0 +1,106 @@
+use gfs::master::{spawn_checkpointer, MasterServer, DEFAULT_DELETION_GRACE_PERIOD};
+use gfs::client::Client;
+use gfs::chunkserver::{spawn_scrubber, ChunkStore, Chunkserver, ChunkserverStorage, DEFAULT_SCRUB_INTERVAL, DEFAULT_SCRUB_RATE};
+use gfs::packed::PackedChunkStorage;
+use gfs::rpc::{serve_chunkserver, serve_master, RemoteMaster, TcpTransport};
+use byte_unit::Byte;
+use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Run each node of a cluster as its own process, talking over TCP.
+//
+//   cargo run --example cluster -- master 127.0.0.1:7000 ./data/master
+//   cargo run --example cluster -- chunkserver 127.0.0.1:7001 127.0.0.1:7000 ./data/chunkserver-1
+//   cargo run --example cluster -- client 127.0.0.1:7000 append /test "hello world"
+//   cargo run --example cluster -- client 127.0.0.1:7000 cat /test
+//
+// Chunkservers are identified by their listen address.
+
+const USAGE: &str = "usage:
+  cluster master <listen-addr> <state-dir> [deletion-grace-secs]
+  cluster chunkserver [--packed] <listen-addr> <master-addr> <storage-dir> [scrub-bytes-per-sec]
+  cluster client <master-addr> (ls <path> | tree <path> | df | du | append <path> <data> | put <path> | cat <path> | read <path> <offset> <length> | rm <path> | undelete <path> | mv [-f] <from> <to> | mkdir <path> | rmdir <path> | snapshot <from> <to>)";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|x| x.as_str()).collect();
+
+    match args.as_slice() {
+        ["master", addr, state_dir] => run_master(addr, PathBuf::from(state_dir), DEFAULT_DELETION_GRACE_PERIOD),
+        ["master", addr, state_dir, grace_secs] => run_master(addr, PathBuf::from(state_dir), Duration::from_secs(grace_secs.parse().unwrap())),
+        ["chunkserver", "--packed", addr, master_addr, storage_dir, scrub_rate @ ..] if scrub_rate.len() <= 1 => {
+            run_chunkserver(addr, master_addr, Box::new(PackedChunkStorage::open(PathBuf::from(storage_dir))), parse_scrub_rate(scrub_rate))
+        }
+        ["chunkserver", addr, master_addr, storage_dir, scrub_rate @ ..] if scrub_rate.len() <= 1 => {
+            run_chunkserver(addr, master_addr, Box::new(ChunkserverStorage::new(PathBuf::from(storage_dir))), parse_scrub_rate(scrub_rate))
+        }
+        ["client", master_addr, cmd @ ..] => run_client(master_addr, cmd),
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_master(addr: &str, state_dir: PathBuf, deletion_grace_period: Duration) {
+    let network = Arc::new(Mutex::new(TcpTransport::new()));
+    let master = Arc::new(Mutex::new(MasterServer::open(network, state_dir).unwrap()));
+    master.lock().unwrap().set_deletion_grace_period(deletion_grace_period);
+    spawn_checkpointer(master.clone(), Duration::from_secs(60));
+    serve_master(master.clone(), addr).unwrap();
+    MasterServer::run(master);
+}
+
+fn parse_scrub_rate(arg: &[&str]) -> u64 {
+    arg.first().map(|x| x.parse().unwrap()).unwrap_or(DEFAULT_SCRUB_RATE)
+}
+
+fn run_chunkserver(addr: &str, master_addr: &str, storage: Box<dyn ChunkStore>, scrub_rate: u64) {
+    let master = Arc::new(RemoteMaster::new(master_addr));
+    let network = Arc::new(Mutex::new(TcpTransport::unrestricted()));
+    let chunkserver = Arc::new(Mutex::new(Chunkserver::new(master, network, addr.to_string(), 1024 * 1024, storage)));
+
+    spawn_scrubber(chunkserver.clone(), scrub_rate, DEFAULT_SCRUB_INTERVAL);
+    serve_chunkserver(chunkserver.clone(), addr).unwrap();
+    Chunkserver::run(chunkserver);
+}
+
+fn run_client(master_addr: &str, cmd: &[&str]) {
+    let network = Arc::new(Mutex::new(TcpTransport::unrestricted()));
+    let client = Client::new(Arc::new(RemoteMaster::new(master_addr)));
+
+    match cmd {
+        ["ls", path] => client.ls(path).unwrap().iter().for_each(|x| println!("{}", x)),
+        ["tree", path] => client.ls_tree(path).unwrap().iter().for_each(|x| println!("{}", x)),
+        ["df"] => println!("disk free: {:#}", Byte::from_u64(client.df().unwrap())),
+        ["du"] => println!("disk used: {:#}", Byte::from_u64(client.du().unwrap())),
+        ["append", path, data] => client.append(path, data.as_bytes(), network).unwrap(),
+        ["put", path] => {
+            // Stream stdin into the file.
+            let mut writer = client.append_writer(path, network);
+            std::io::copy(&mut std::io::stdin(), &mut writer).unwrap();
+            writer.close().unwrap();
+        }
+        ["cat", path] => {
+            let mut file = client.open(path, network).unwrap();
+            std::io::copy(&mut file, &mut std::io::stdout()).unwrap();
+            println!();
+        }
+        ["read", path, offset, length] => {
+            let data = client.read(path, offset.parse().unwrap(), length.parse().unwrap(), network).unwrap();
+            println!("{}", String::from_utf8_lossy(&data));
+        }
+        ["rm", path] => client.delete(path).unwrap(),
+        ["undelete", path] => client.undelete(path).unwrap(),
+        ["mv", from, to] => client.rename(from, to, false).unwrap(),
+        ["mv", "-f", from, to] => client.rename(from, to, true).unwrap(),
+        ["mkdir", path] => client.mkdir(path).unwrap(),
+        ["rmdir", path] => client.rmdir(path).unwrap(),
+        ["snapshot", from, to] => client.snapshot(from, to).unwrap(),
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use crate::master::{*};
+use crate::chunk::{*};
+use crate::chunkserver::ChunkserverError;
+use crate::client::{ClientError, add_chunk_location, chunk_placements, chunk_range, replica_pushes, DEFAULT_PUSH_WINDOW};
+use crate::rpc::{AsyncRemoteChunkserver, AsyncRemoteMaster};
+
+/// An async client, for use from tokio.
+///
+/// The client talks to the master and chunkservers over TCP with tokio sockets, so no call
+/// blocks a runtime thread. Chunkservers are reached at their IDs, which are their `host:port`
+/// listen addresses (see `TcpTransport`). Chunks are pushed to, and read from, chunkservers
+/// concurrently, at most `push_window` at a time.
+pub struct AsyncClient {
+    master: AsyncRemoteMaster,
+    /// The most chunk pushes, or reads, to make at once.
+    push_window: usize,
+}
+
+impl AsyncClient {
+    /// A client of the master listening on `master_addr`.
+    pub fn new(master_addr: &str) -> AsyncClient {
+        AsyncClient { master: AsyncRemoteMaster::new(master_addr), push_window: DEFAULT_PUSH_WINDOW }
+    }
+
+    /// Set the most chunk pushes, or reads, to make at once.
+    pub fn set_push_window(&mut self, push_window: usize) {
+        self.push_window = push_window;
+    }
+
+    /// Get a file's metadata.
+    pub async fn stat(&self, path: &str) -> Result<StatInfo, MasterError> {
+        self.master.stat(path).await
+    }
+
+    /// List a directory. Subdirectories end with a `/`.
+    pub async fn ls(&self, path: &str) -> Result<Vec<String>, MasterError> {
+        self.master.ls(path).await
+    }
+
+    /// Read up to `length` bytes of a file from `offset`. See `Client::read`.
+    pub async fn read(&self, path: &str, offset: u64, length: u64) -> Result<Vec<u8>, ClientError> {
+        // 1. Get the chunks covering the range, and their locations, from the master.
+        let read_info = self.master.get_read_infos(path, offset, length).await.map_err(ClientError::Master)?;
+
+        // 2. Read the chunks, up to `push_window` at the same time.
+        let mut chunks = vec![vec![]; read_info.chunk_reads.len()];
+        let mut reads = JoinSet::new();
+        let mut chunk_reads = read_info.chunk_reads.into_iter().enumerate();
+        loop {
+            while reads.len() < self.push_window.max(1) {
+                let Some((i, chunk_read)) = chunk_reads.next() else { break };
+                reads.spawn(async move { (i, read_chunk(&chunk_read).await) });
+            }
+            let Some(res) = reads.join_next().await else { break };
+            let (i, chunk_data) = res.unwrap();
+            chunks[i] = chunk_data.map_err(ClientError::Chunkserver)?;
+        }
+
+        Ok(chunks.concat())
+    }
+
+    /// Append data to a file. See `Client::append`.
+    pub async fn append(&self, path: &str, data: &[u8]) -> Result<(), ClientError> {
+        let append_length = data.len() as u64;
+        if append_length > 1_000_000_000 {
+            return Err(ClientError::AppendTooLarge);
+        }
+
+        // 1. Divide the data into chunks.
+        let chunks = Arc::new(data_to_chunks(data));
+
+        // 2. Ask master for free chunkservers.
+        let free_chunkservers = self.master.get_free_chunkservers(chunks.len() as u64, REPLICATION_FACTOR as u8).await
+            .map_err(ClientError::Master)?;
+        if free_chunkservers.len() < REPLICATION_FACTOR {
+            return Err(ClientError::NotEnoughChunkservers);
+        }
+        let free_chunkservers = Arc::new(free_chunkservers);
+
+        // 3. Push every replica, up to `push_window` at the same time.
+        let mut chunk_locations: HashMap<ChunkHash, Vec<String>> = HashMap::new();
+        let placements = chunk_placements(&chunks);
+        let mut replicas = replica_pushes(&placements, free_chunkservers.len());
+        let mut pushes = JoinSet::new();
+        loop {
+            while pushes.len() < self.push_window.max(1) {
+                let Some((i, index)) = replicas.next() else { break };
+                let (chunks, free_chunkservers) = (chunks.clone(), free_chunkservers.clone());
+                pushes.spawn(async move {
+                    AsyncRemoteChunkserver::new(&free_chunkservers[index]).push_chunk(&chunks[i].data).await?;
+                    Ok::<_, ChunkserverError>((i, index))
+                });
+            }
+            let Some(res) = pushes.join_next().await else { break };
+            let (i, index) = res.unwrap().map_err(ClientError::Chunkserver)?;
+            add_chunk_location(&mut chunk_locations, chunks[i].hash, &free_chunkservers[index]);
+        }
+
+        // 4. Commit the chunks at the master, as one append.
+        let op = AppendOperation {
+            file_path: path.to_string(),
+            length: append_length,
+            chunk_sequence: chunks.iter().map(|chunk| chunk.hash).collect(),
+            chunk_locations,
+        };
+        self.master.append_file(&op).await.map_err(ClientError::Master)
+    }
+}
+
+/// Read the range of a chunk in a `ChunkRead`, from the first replica which responds.
+/// See `client::read_chunk`.
+async fn read_chunk(chunk_read: &ChunkRead) -> Result<Vec<u8>, ChunkserverError> {
+    let mut res = Err(ChunkserverError::Unavailable);
+    for location in chunk_read.locations.iter() {
+        res = AsyncRemoteChunkserver::new(location).read_chunk(chunk_read.chunk_id).await
+            .and_then(|chunk_data| chunk_range(chunk_read, &chunk_data));
+        if res.is_ok() {
+            break;
+        }
+    }
+    res
+}
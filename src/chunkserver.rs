@@ -1,26 +1,49 @@
-use std::collections::HashMap;
-use serde::{Serialize, Deserialize};
-use std::path::Path;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::vec;
-use sha2::Digest;
-use lru::LruCache;
-use std::num::NonZeroUsize;
-use crate::master::MasterServer;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use crate::common::{*};
+use crate::master::{Heartbeat, HEARTBEAT_INTERVAL};
 use crate::chunk::{*};
 
+/// How long pushed data is kept waiting for the master to commit it.
+pub const PUSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many bytes of chunk data the scrubber reads per second, by default.
+pub const DEFAULT_SCRUB_RATE: u64 = 1024 * 1024;
+
+/// How often the scrubber starts a pass over every chunk, by default.
+pub const DEFAULT_SCRUB_INTERVAL: Duration = Duration::from_secs(60);
+
 pub struct Chunkserver {
-    master: Arc<Mutex<MasterServer>>,
+    master: Arc<dyn MasterHandle>,
+    network: Arc<Mutex<dyn Transport>>,
     pub id: String,
     disk_allocation: u64,
 
-    /// The LRU cache for chunks.
-    lru_cache: LruCache<[u8; 32], Vec<u8>>,
+    /// Chunks pushed by clients, kept until the master commits them.
+    pushed_chunks: HashMap<ChunkHash, PushedChunk>,
 
     /// The storage for the chunkserver.
-    storage: ChunkserverStorage,
+    storage: Box<dyn ChunkStore>,
+
+    /// Whether the next heartbeat should report every chunk held.
+    full_report_needed: bool,
+
+    /// Chunks committed since the last heartbeat, and their versions.
+    added_chunks: HashMap<u64, u64>,
+
+    /// Chunks deleted since the last heartbeat.
+    removed_chunks: Vec<u64>,
+
+    /// Chunks found to be corrupt since the last heartbeat, and dropped.
+    corrupt_chunks: Vec<u64>,
+
+    /// The progress of the background scrubber.
+    scrub_progress: ScrubProgress,
 }
 
 
@@ -28,100 +51,381 @@ pub struct Chunkserver {
 pub enum ChunkserverError {
     InvalidChunkLength,
     ChunkNotFound,
+    /// The chunkserver could not be reached.
+    Unavailable,
+    /// The chunk's data didn't match its checksums.
+    Corrupted,
+    /// The chunk couldn't be read from or written to disk. Unlike `Corrupted`, the replica
+    /// may be intact, so it is kept.
+    StorageFailed,
+}
+
+/// A chunk pushed by clients, waiting to be committed.
+struct PushedChunk {
+    /// When it was last pushed.
+    pushed: Instant,
+    data: Vec<u8>,
+    /// The number of times it has been pushed, less the number of times it has been committed.
+    /// An append repeating a chunk pushes and commits it once for each time it appears.
+    pushes: usize,
 }
 
 pub struct Chunk {
     pub id: u64,
+    /// The version of the chunk, as granted by the master. Older versions are stale.
+    pub version: u64,
     pub len: u64,
-    pub checksum: u32,
+    /// The CRC32 of each block of the chunk.
+    pub checksums: Vec<u32>,
 }
 
+/// Chunks are checksummed in blocks of this size, so corruption can be found without
+/// trusting the whole chunk. GFS uses 64KB blocks of 64MB chunks.
+pub const CHECKSUM_BLOCK_BYTES: usize = 256;
+
+/// Compute the checksum of each block of a chunk.
+pub fn block_checksums(data: &[u8]) -> Vec<u32> {
+    data.chunks(CHECKSUM_BLOCK_BYTES).map(crc32fast::hash).collect()
+}
+/// Where a chunkserver keeps its chunks: one file per chunk (`ChunkserverStorage`), or
+/// packed into segment files (`PackedChunkStorage`).
+pub trait ChunkStore: Send {
+    /// Write a version of a chunk, replacing any other version stored.
+    /// The chunk is durable once this returns. If it fails, the version stored is unchanged.
+    fn write_chunk(&mut self, chunk_id: u64, version: u64, data: &[u8]) -> std::io::Result<()>;
+
+    /// Read a chunk, verifying each of its blocks against its checksum.
+    fn read_chunk(&self, chunk_id: u64) -> Result<Vec<u8>, ChunkserverError>;
+
+    /// Raise the version of a stored chunk, keeping its data.
+    /// The new version is durable once this returns. If it fails, the version stored is unchanged.
+    fn set_chunk_version(&mut self, chunk_id: u64, version: u64) -> std::io::Result<()>;
+
+    /// Delete a chunk, returning whether it was stored.
+    fn delete_chunk(&mut self, chunk_id: u64) -> bool;
 
+    /// The version of a chunk stored, if any.
+    fn chunk_version(&self, chunk_id: u64) -> Option<u64>;
 
+    /// The chunks stored, and their versions.
+    fn chunk_versions(&self) -> HashMap<u64, u64>;
+
+    /// The number of bytes of chunk data stored.
+    fn disk_used(&self) -> u64;
+
+    /// The number of bytes free on the filesystem holding the store.
+    fn disk_available(&self) -> std::io::Result<u64>;
+}
+
+/// Stores each chunk in its own file.
+///
+/// Each chunk (`ch{id}.{version}`) and its block checksums (`.crc`) are written to temporary
+/// files, fsynced and renamed into place, so a crash leaves a chunk either complete or absent.
+/// Temporary files, short chunk files and checksums without a chunk left by a crash are
+/// removed when the storage is opened.
 pub struct ChunkserverStorage {
     // The path to the chunkserver storage directory.
     storage_dir: PathBuf,
 
-    // List of chunks.
-    chunks: Vec<Chunk>,
+    // The chunks stored, by ID.
+    chunks: HashMap<u64, Chunk>,
 }
 
 
 impl ChunkserverStorage {
     pub fn new(storage_dir: PathBuf) -> ChunkserverStorage {
-        use crc32fast::Hasher;
-
         // If directory does not exist, create it.
         if !storage_dir.exists() {
             std::fs::create_dir_all(&storage_dir).unwrap();
         }
 
-        let mut chunks = Vec::new();
+        let mut chunks: HashMap<u64, Chunk> = HashMap::new();
+
+        // Chunks written before versioning are named `ch{id}`. They are version 0, so rename
+        // them to match, as every other operation on the chunk uses the versioned name.
+        for file in std::fs::read_dir(&storage_dir).unwrap() {
+            let name = file.unwrap().file_name().into_string().unwrap();
+            let Some(chunk_id) = name.strip_prefix("ch").and_then(|x| x.parse::<u64>().ok()) else { continue };
+            println!("[chunkserver] renaming {name} to {}", chunk_file_name(chunk_id, 0));
+            std::fs::rename(storage_dir.join(&name), storage_dir.join(chunk_file_name(chunk_id, 0))).unwrap();
+        }
+        sync_dir(&storage_dir).unwrap();
 
         // List all files.
-        let files = std::fs::read_dir(&storage_dir).unwrap();
-        for file in files {
-            let file = file.unwrap();
-            let name = file.file_name();
-            
+        let names: HashSet<String> = std::fs::read_dir(&storage_dir).unwrap()
+            .map(|file| file.unwrap().file_name().into_string().unwrap())
+            .collect();
+        for name in names.iter() {
+            let path = storage_dir.join(name);
+
+            // Clean up writes interrupted by a crash.
+            if name.ends_with(".tmp") {
+                println!("[chunkserver] removing partially written file {name}");
+                std::fs::remove_file(&path).unwrap();
+                continue;
+            }
+            if let Some(chunk_name) = name.strip_suffix(".crc") {
+                if !names.contains(chunk_name) {
+                    println!("[chunkserver] removing checksums {name} of missing chunk");
+                    std::fs::remove_file(&path).unwrap();
+                }
+                continue;
+            }
+
             // if name begins with ch
-            if name.to_str().unwrap().starts_with("ch") {
-                // parse the chunk ID
-                let chunk_id = name.to_str().unwrap().split_at(2).1.parse::<u64>().unwrap();
-                // compute the checksum
-                // load chunk data
-                let mut hasher = Hasher::new();
+            if let Some((chunk_id, version)) = parse_chunk_file_name(name) {
                 // ensure file is CHUNK SIZE bytes
-                if file.metadata().unwrap().len() != CHUNK_SIZE_BYTES as u64 {
+                let len = std::fs::metadata(&path).unwrap().len();
+                if len != CHUNK_SIZE_BYTES as u64 {
+                    println!("[chunkserver] removing chunk {name}: {len} bytes, expected {CHUNK_SIZE_BYTES}");
+                    remove_chunk_files(&storage_dir, chunk_id, version);
                     continue;
                 }
-                let data = std::fs::read(file.path()).unwrap();
-                let checksum = crc32fast::hash(&data);
-                let chunk = Chunk { id: chunk_id, len: data.len() as u64, checksum };
 
-                println!("Chunk: {chunk_id} {checksum}");
+                // Only the newest version of a chunk is kept, as when it is written.
+                match chunks.get(&chunk_id) {
+                    Some(stored) if stored.version > version => {
+                        remove_chunk_files(&storage_dir, chunk_id, version);
+                        continue;
+                    }
+                    Some(stored) => remove_chunk_files(&storage_dir, chunk_id, stored.version),
+                    None => {}
+                }
+
+                // Load the block checksums, computing them for chunks stored before they were kept.
+                let checksums_path = storage_dir.join(checksums_file_name(chunk_id, version));
+                let checksums = match std::fs::read(&checksums_path) {
+                    Ok(bytes) if bytes.len() == CHUNK_SIZE_BYTES.div_ceil(CHECKSUM_BLOCK_BYTES) * 4 => decode_checksums(&bytes),
+                    _ => {
+                        let checksums = block_checksums(&std::fs::read(&path).unwrap());
+                        write_file_durably(&checksums_path, &encode_checksums(&checksums)).unwrap();
+                        checksums
+                    }
+                };
+
+                println!("Chunk: {chunk_id} v{version}");
                 // add to chunks
-                chunks.push(chunk);
+                chunks.insert(chunk_id, Chunk { id: chunk_id, version, len, checksums });
             }
         }
+        sync_dir(&storage_dir).unwrap();
         ChunkserverStorage { storage_dir, chunks }
     }
+}
 
-    pub fn write_chunk(&mut self, chunk_id: u64, data: &[u8]) {
-        // Write the data to disk in the storage directory.
-        let chunk_path = self.storage_dir.join(format!("ch{chunk_id}"));
-        std::fs::write(chunk_path, data).unwrap();
-
-        // Compute checksum.
-        let checksum = crc32fast::hash(data);
+impl ChunkStore for ChunkserverStorage {
+    /// A crash part way through leaves either the previous version or the new one,
+    /// along with temporary files which are removed at startup.
+    fn write_chunk(&mut self, chunk_id: u64, version: u64, data: &[u8]) -> std::io::Result<()> {
+        // Write the checksums, then the data, to disk in the storage directory.
+        // A chunk file is only ever renamed into place complete, with its checksums already there.
+        let checksums = block_checksums(data);
+        write_file_durably(&self.storage_dir.join(checksums_file_name(chunk_id, version)), &encode_checksums(&checksums))?;
+        write_file_durably(&self.storage_dir.join(chunk_file_name(chunk_id, version)), data)?;
+        sync_dir(&self.storage_dir)?;
+
+        // Only then remove the version it replaces.
+        if let Some(previous) = self.chunks.remove(&chunk_id) {
+            if previous.version != version {
+                remove_chunk_files(&self.storage_dir, chunk_id, previous.version);
+            }
+        }
 
         // Add the chunk to the chunk list.
-        self.chunks.push(Chunk { id: chunk_id, len: data.len() as u64, checksum: checksum });
+        self.chunks.insert(chunk_id, Chunk { id: chunk_id, version, len: data.len() as u64, checksums });
+        Ok(())
+    }
+
+    fn read_chunk(&self, chunk_id: u64) -> Result<Vec<u8>, ChunkserverError> {
+        let chunk = self.chunks.get(&chunk_id).ok_or(ChunkserverError::ChunkNotFound)?;
+        let chunk_path = self.storage_dir.join(chunk_file_name(chunk.id, chunk.version));
+        let data = std::fs::read(chunk_path).map_err(storage_failed)?;
+        if block_checksums(&data) != chunk.checksums {
+            return Err(ChunkserverError::Corrupted);
+        }
+        Ok(data)
+    }
+
+    /// The chunk is renamed, rather than copied. Its checksums are written under the new
+    /// version first, so a crash part way through leaves a chunk file with its checksums.
+    fn set_chunk_version(&mut self, chunk_id: u64, version: u64) -> std::io::Result<()> {
+        let Some(chunk) = self.chunks.get_mut(&chunk_id) else {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "chunk not found"));
+        };
+        let previous = chunk.version;
+        write_file_durably(&self.storage_dir.join(checksums_file_name(chunk_id, version)), &encode_checksums(&chunk.checksums))?;
+        std::fs::rename(self.storage_dir.join(chunk_file_name(chunk_id, previous)), self.storage_dir.join(chunk_file_name(chunk_id, version)))?;
+        sync_dir(&self.storage_dir)?;
+        chunk.version = version;
+        let _ = std::fs::remove_file(self.storage_dir.join(checksums_file_name(chunk_id, previous)));
+        Ok(())
+    }
+
+    fn delete_chunk(&mut self, chunk_id: u64) -> bool {
+        let Some(chunk) = self.chunks.remove(&chunk_id) else { return false };
+        remove_chunk_files(&self.storage_dir, chunk.id, chunk.version);
+        true
+    }
+
+    fn chunk_version(&self, chunk_id: u64) -> Option<u64> {
+        self.chunks.get(&chunk_id).map(|x| x.version)
     }
 
+    fn chunk_versions(&self) -> HashMap<u64, u64> {
+        self.chunks.values().map(|x| (x.id, x.version)).collect()
+    }
+
+    fn disk_used(&self) -> u64 {
+        self.chunks.values().map(|x| x.len).sum()
+    }
+
+    fn disk_available(&self) -> std::io::Result<u64> {
+        filesystem_available(&self.storage_dir)
+    }
+}
+
+/// The number of bytes free to unprivileged users on the filesystem holding `path`.
+pub(crate) fn filesystem_available(path: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid C string, and `stat` is a valid statvfs to write to.
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Chunks are stored in files named `ch{id}.{version}`.
+fn chunk_file_name(chunk_id: u64, version: u64) -> String {
+    format!("ch{chunk_id}.{version}")
+}
+
+/// The checksums of each chunk are kept alongside it in `ch{id}.{version}.crc`,
+/// as little-endian CRC32s, one per block.
+fn checksums_file_name(chunk_id: u64, version: u64) -> String {
+    format!("ch{chunk_id}.{version}.crc")
+}
+
+/// Remove a version of a chunk and its checksums from disk.
+fn remove_chunk_files(storage_dir: &Path, chunk_id: u64, version: u64) {
+    // Either may be missing, if the chunk was corrupt or stored before checksums were kept.
+    let _ = std::fs::remove_file(storage_dir.join(chunk_file_name(chunk_id, version)));
+    let _ = std::fs::remove_file(storage_dir.join(checksums_file_name(chunk_id, version)));
+}
+
+/// Write a file so that it is either complete or absent after a crash: write a temporary
+/// file, fsync it, and rename it over `path`. The directory must be synced for the rename
+/// itself to survive a crash.
+pub(crate) fn write_file_durably(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// A chunk couldn't be read from or written to disk.
+pub(crate) fn storage_failed(err: std::io::Error) -> ChunkserverError {
+    println!("[chunkserver] chunk storage failed: {err}");
+    ChunkserverError::StorageFailed
+}
+
+fn encode_checksums(checksums: &[u32]) -> Vec<u8> {
+    checksums.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn decode_checksums(bytes: &[u8]) -> Vec<u32> {
+    bytes.chunks_exact(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect()
+}
+
+/// Parse a chunk file name into the chunk ID and version.
+fn parse_chunk_file_name(name: &str) -> Option<(u64, u64)> {
+    let (chunk_id, version) = name.strip_prefix("ch")?.split_once('.')?;
+    Some((chunk_id.parse().ok()?, version.parse().ok()?))
 }
 
 impl Chunkserver {
-    pub fn new(master: Arc<Mutex<MasterServer>>, id: String, disk_allocation: u64, storage: ChunkserverStorage) -> Chunkserver {
+    pub fn new(master: Arc<dyn MasterHandle>, network: Arc<Mutex<dyn Transport>>, id: String, disk_allocation: u64, storage: Box<dyn ChunkStore>) -> Chunkserver {
         Chunkserver { 
             master, 
+            network,
             id,
             disk_allocation,
-            lru_cache: LruCache::new(NonZeroUsize::new(20).unwrap()),
+            pushed_chunks: HashMap::new(),
             storage,
+            full_report_needed: true,
+            added_chunks: HashMap::new(),
+            removed_chunks: vec![],
+            corrupt_chunks: vec![],
+            scrub_progress: ScrubProgress::default(),
         }
     }
 
-    pub fn run(&self) {
-        // Run the chunkserver.
-        self.master.lock().unwrap().receive_heartbeat(
-            self.id.clone(),
-            0,
-            self.disk_allocation,
-        );
+    /// Send heartbeats to the master, forever.
+    ///
+    /// The chunkserver is only locked while building each heartbeat, as the master
+    /// may call into the chunkserver while holding its own lock.
+    pub fn run(chunkserver: Arc<Mutex<Chunkserver>>) {
+        loop {
+            let (master, heartbeat) = {
+                let mut chunkserver = chunkserver.lock().unwrap();
+                (chunkserver.master.clone(), chunkserver.heartbeat())
+            };
+            let res = master.receive_heartbeat(heartbeat);
+            {
+                let mut chunkserver = chunkserver.lock().unwrap();
+                chunkserver.full_report_needed |= res.full_report_needed;
+                chunkserver.delete_chunks(&res.delete_chunks);
+                chunkserver.expire_pushes();
+            }
+            std::thread::sleep(HEARTBEAT_INTERVAL);
+        }
+    }
+
+    /// Record a stored chunk for the next heartbeat.
+    fn chunk_added(&mut self, chunk_id: u64, version: u64) {
+        self.removed_chunks.retain(|x| *x != chunk_id);
+        self.added_chunks.insert(chunk_id, version);
+    }
+
+    /// Build a status report for the master, including the chunks changed since the last one.
+    pub fn heartbeat(&mut self) -> Heartbeat {
+        let disk_used = self.storage.disk_used();
+        let full_report = std::mem::take(&mut self.full_report_needed);
+        let (added_chunks, removed_chunks) = if full_report {
+            self.added_chunks.clear();
+            self.removed_chunks.clear();
+            (self.storage.chunk_versions(), vec![])
+        } else {
+            (std::mem::take(&mut self.added_chunks), std::mem::take(&mut self.removed_chunks))
+        };
+        // The allocation may be more than the disk has left, e.g. if it is shared.
+        let allocation_free = self.disk_allocation.saturating_sub(disk_used);
+        let disk_free = match self.storage.disk_available() {
+            Ok(available) => allocation_free.min(available),
+            Err(err) => {
+                println!("[chunkserver] {} couldn't query free disk space: {err}", self.id);
+                allocation_free
+            }
+        };
+        Heartbeat {
+            chunkserver_id: self.id.clone(),
+            disk_used,
+            disk_free,
+            full_report,
+            added_chunks,
+            removed_chunks,
+            corrupt_chunks: std::mem::take(&mut self.corrupt_chunks),
+        }
     }
     
-    /// Receive a chunk datum pushed by a client into the LRU cache.
+    /// Receive a chunk datum pushed by a client, holding it until it is committed.
+    ///
+    /// Pushed chunks are never evicted to make room for others, so every chunk of an append
+    /// can be pushed before any is committed. Chunks which are never committed expire after
+    /// `PUSH_TIMEOUT`.
     pub fn push_chunk(&mut self, data: &[u8]) -> Result<(), ChunkserverError> {
         if data.len() != CHUNK_SIZE_BYTES {
             return Err(ChunkserverError::InvalidChunkLength);
@@ -130,44 +434,189 @@ impl Chunkserver {
         // Compute the chunk datum ID (SHA256).
         let chunk_hash = sha256sum(data);
 
-        // Insert the chunk into the LRU cache.
-        self.lru_cache.put(chunk_hash, data.to_vec());
+        let pushed = self.pushed_chunks.entry(chunk_hash).or_insert_with(|| PushedChunk { pushed: Instant::now(), data: data.to_vec(), pushes: 0 });
+        pushed.pushed = Instant::now();
+        pushed.pushes += 1;
 
         Ok(())
     }
 
-    /// Commit a datum from LRU cache to disk.
+    /// Commit a pushed datum to disk.
     /// This is called by the master server.
-    pub fn commit_chunk(&mut self, chunk_hash: ChunkHash, chunk_id: u64) -> Result<(), ChunkserverError> {
-        // Get the value from LRU, if it is missing return error.
-        let value_res = self.lru_cache.get(&chunk_hash);
-        if value_res.is_none() {
+    pub fn commit_chunk(&mut self, chunk_hash: ChunkHash, chunk_id: u64, version: u64) -> Result<(), ChunkserverError> {
+        // Get the pushed datum, if it is missing return error.
+        let Some(pushed) = self.pushed_chunks.get_mut(&chunk_hash) else {
             return Err(ChunkserverError::ChunkNotFound);
-        }
+        };
 
         // Store a chunk on disk with the ID from the master.
-        // Write the data to disk in the storage directory.
-        self.storage.write_chunk(chunk_id, value_res.unwrap());
+        // Write the data to disk in the storage directory. If that fails, the datum is kept.
+        self.storage.write_chunk(chunk_id, version, &pushed.data).map_err(storage_failed)?;
+
+        // Drop the datum once it has been committed as many times as it was pushed.
+        pushed.pushes -= 1;
+        if pushed.pushes == 0 {
+            self.pushed_chunks.remove(&chunk_hash);
+        }
+        self.chunk_added(chunk_id, version);
+
+        Ok(())
+    }
+
+    /// Copy a chunk from another chunkserver.
+    /// This is called by the master to re-replicate chunks.
+    pub fn replicate_chunk(&mut self, chunk_id: u64, version: u64, source: &str) -> Result<(), ChunkserverError> {
+        if self.storage.chunk_version(chunk_id).is_some_and(|x| x >= version) {
+            return Ok(());
+        }
 
-        // Remove the datum from the LRU cache.
-        self.lru_cache.pop(&chunk_hash);
+        let source = self.network.lock().unwrap().get_node(source).ok_or(ChunkserverError::Unavailable)?;
+        // The source checks the data against its checksums before sending it.
+        let data = source.read_chunk(chunk_id)?;
+        self.storage.write_chunk(chunk_id, version, &data).map_err(storage_failed)?;
+        self.chunk_added(chunk_id, version);
 
         Ok(())
     }
 
+    /// Raise the version of a chunk held, as the master grants a mutation on it.
+    /// Replicas which aren't told the new version are stale.
+    pub fn update_chunk_version(&mut self, chunk_id: u64, version: u64) -> Result<(), ChunkserverError> {
+        let stored = self.storage.chunk_version(chunk_id).ok_or(ChunkserverError::ChunkNotFound)?;
+        if stored >= version {
+            return Ok(());
+        }
+        self.storage.set_chunk_version(chunk_id, version).map_err(storage_failed)?;
+        self.chunk_added(chunk_id, version);
+        Ok(())
+    }
+
+    /// Delete chunks the master has found to be stale or unneeded, if held at or below the given version.
+    pub fn delete_chunks(&mut self, chunks: &HashMap<u64, u64>) {
+        for (chunk_id, version) in chunks {
+            if self.storage.chunk_version(*chunk_id).is_none_or(|x| x > *version) {
+                continue;
+            }
+            if self.storage.delete_chunk(*chunk_id) {
+                println!("[chunkserver] {} deleted chunk {chunk_id}", self.id);
+                self.added_chunks.remove(chunk_id);
+                self.removed_chunks.push(*chunk_id);
+            }
+        }
+    }
+
+    /// Drop pushed data which was never committed.
+    fn expire_pushes(&mut self) {
+        self.pushed_chunks.retain(|_, pushed| pushed.pushed.elapsed() <= PUSH_TIMEOUT);
+    }
+
     /// Read a chunk from the storage.
-    /// This is called by clients.
-    pub fn read_chunk(&self, chunk_id: u64) -> Result<Vec<u8>, ChunkserverError> {
-        // Find the chunk in the storage.
-        let chunk = self.storage.chunks.iter().find(|c| c.id == chunk_id);
-        if chunk.is_none() {
-            return Err(ChunkserverError::ChunkNotFound);
+    /// This is called by clients, and by other chunkservers re-replicating the chunk.
+    ///
+    /// A corrupt replica is dropped and reported to the master, which copies the chunk
+    /// from another replica. The caller should read from another replica too, as it should
+    /// when the replica can't be read, though that replica is kept.
+    pub fn read_chunk(&mut self, chunk_id: u64) -> Result<Vec<u8>, ChunkserverError> {
+        let res = self.storage.read_chunk(chunk_id);
+        if let Err(ChunkserverError::Corrupted) = res {
+            self.chunk_corrupted(chunk_id);
         }
+        res
+    }
 
-        // Read the chunk from disk.
-        let chunk_path = self.storage.storage_dir.join(format!("ch{chunk_id}"));
-        let data = std::fs::read(chunk_path).unwrap();
+    /// Drop a replica which failed its checksums, and report it in the next heartbeat.
+    fn chunk_corrupted(&mut self, chunk_id: u64) {
+        println!("[chunkserver] {} chunk {chunk_id} is corrupt", self.id);
+        if self.storage.delete_chunk(chunk_id) {
+            self.added_chunks.remove(&chunk_id);
+            self.corrupt_chunks.push(chunk_id);
+        }
+    }
+}
 
-        Ok(data)
+//
+// Scrubbing.
+//
+
+/// The progress of the background scrubber.
+#[derive(Debug, Clone, Default)]
+pub struct ScrubProgress {
+    /// The number of passes over every chunk finished.
+    pub passes: u64,
+    /// The number of chunks checked in the current pass.
+    pub chunks_scrubbed: u64,
+    /// The number of chunks held when the current pass started.
+    pub chunks_total: u64,
+    /// Bytes of chunk data checked, over every pass.
+    pub bytes_scrubbed: u64,
+    /// Corrupt chunks found, over every pass.
+    pub corrupt_chunks: u64,
+    /// Chunks which couldn't be read from disk, over every pass.
+    pub read_errors: u64,
+}
+
+impl Chunkserver {
+    /// The progress of the background scrubber.
+    ///
+    /// This isn't served over RPC. The scrubber prints a summary after each pass instead.
+    pub fn scrub_progress(&self) -> ScrubProgress {
+        self.scrub_progress.clone()
+    }
+
+    /// Verify one chunk's checksums, returning the number of bytes read.
+    fn scrub_chunk(&mut self, chunk_id: u64) -> u64 {
+        let len = match self.storage.read_chunk(chunk_id) {
+            Ok(data) => data.len() as u64,
+            Err(ChunkserverError::Corrupted) => {
+                self.chunk_corrupted(chunk_id);
+                self.scrub_progress.corrupt_chunks += 1;
+                CHUNK_SIZE_BYTES as u64
+            }
+            // The replica may be intact, so it is checked again in the next pass.
+            Err(ChunkserverError::StorageFailed) => {
+                self.scrub_progress.read_errors += 1;
+                0
+            }
+            // The chunk was deleted since the pass started.
+            Err(_) => 0,
+        };
+        self.scrub_progress.chunks_scrubbed += 1;
+        self.scrub_progress.bytes_scrubbed += len;
+        len
     }
-}
\ No newline at end of file
+}
+
+/// Verify the checksums of every chunk held on a background thread, reading at most
+/// `bytes_per_sec` (or as fast as possible, if 0), and starting a pass at most every `interval`.
+///
+/// Reads only catch corruption in chunks which are read. This finds it in idle chunks,
+/// so the master can re-replicate them while other replicas are intact.
+pub fn spawn_scrubber(chunkserver: Arc<Mutex<Chunkserver>>, bytes_per_sec: u64, interval: Duration) -> JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        let started = Instant::now();
+        let mut chunks: Vec<u64> = {
+            let mut chunkserver = chunkserver.lock().unwrap();
+            let chunks: Vec<u64> = chunkserver.storage.chunk_versions().into_keys().collect();
+            chunkserver.scrub_progress.chunks_scrubbed = 0;
+            chunkserver.scrub_progress.chunks_total = chunks.len() as u64;
+            chunks
+        };
+        chunks.sort();
+
+        // The chunkserver is only locked while checking each chunk, so it keeps serving requests.
+        for chunk_id in chunks {
+            let len = chunkserver.lock().unwrap().scrub_chunk(chunk_id);
+            if bytes_per_sec > 0 {
+                std::thread::sleep(Duration::from_secs_f64(len as f64 / bytes_per_sec as f64));
+            }
+        }
+
+        {
+            let mut chunkserver = chunkserver.lock().unwrap();
+            chunkserver.scrub_progress.passes += 1;
+            let ScrubProgress { passes, chunks_scrubbed, corrupt_chunks, read_errors, .. } = chunkserver.scrub_progress;
+            println!("[chunkserver] {} scrub pass {passes} checked {chunks_scrubbed} chunks ({corrupt_chunks} corrupt and {read_errors} unreadable found in total)", chunkserver.id);
+        }
+        std::thread::sleep(interval.saturating_sub(started.elapsed()));
+    })
+}
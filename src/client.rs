@@ -1,87 +1,147 @@
 use std::collections::HashMap;
-use std::fmt::Error;
 use std::sync::{Arc, Mutex};
-use std::vec;
-use crate::chunkserver::{*};
 use crate::common::{*};
 use crate::master::{*};
-use crate::chunk::{self, *};
+use crate::chunk::{*};
+use crate::chunkserver::ChunkserverError;
 
 
+#[derive(Debug)]
 pub enum ClientError {
     AppendTooLarge,
     NotEnoughChunkservers,
+    Master(MasterError),
+    Chunkserver(ChunkserverError),
 }
 
+/// The number of chunk pushes a client makes at once, by default.
+pub const DEFAULT_PUSH_WINDOW: usize = 8;
+
 pub struct Client {
-    master: Arc<Mutex<MasterServer>>,
+    master: Arc<dyn MasterHandle>,
+    /// The most chunk pushes to make at once.
+    push_window: usize,
 }
 
 impl Client {
-    pub fn new(master: Arc<Mutex<MasterServer>>) -> Client {
-        Client { master }
+    pub fn new(master: Arc<dyn MasterHandle>) -> Client {
+        Client { master, push_window: DEFAULT_PUSH_WINDOW }
+    }
+
+    /// Set the most chunk pushes to make at once, when appending.
+    pub fn set_push_window(&mut self, push_window: usize) {
+        self.push_window = push_window;
     }
 
     /// Get the total number of bytes free in the filesystem (disk free).
-    pub fn df(&self) -> u64 {
-        self.master.lock().unwrap().df()
+    pub fn df(&self) -> Result<u64, MasterError> {
+        self.master.df()
     }
 
     /// Get the total number of bytes used in the filesystem (disk used).
-    pub fn du(&self) -> u64 {
-        self.master.lock().unwrap().du()
+    pub fn du(&self) -> Result<u64, MasterError> {
+        self.master.du()
+    }
+
+    /// List a directory. Subdirectories end with a `/`.
+    pub fn ls(&self, path: &str) -> Result<Vec<String>, MasterError> {
+        self.master.ls(path)
+    }
+
+    /// List the file tree under a path (akin to `tree`).
+    pub fn ls_tree(&self, path: &str) -> Result<Vec<String>, MasterError> {
+        self.master.ls_tree(path)
     }
 
-    /// List the files in a directory.
-    pub fn ls(&self, path: &str) -> Vec<String> {
-        self.master.lock().unwrap().ls(path)
+    /// Delete a file. It can be restored with `undelete` until the master reclaims it.
+    pub fn delete(&self, path: &str) -> Result<(), MasterError> {
+        self.master.delete(path)
     }
 
-    /// List the file tree for a path prefix (akin to `tree`).
-    pub fn ls_tree(&self, path: &str) -> Vec<String> {
-        self.master.lock().unwrap().ls_tree(path)
+    /// Restore the most recently deleted file at a path.
+    pub fn undelete(&self, path: &str) -> Result<(), MasterError> {
+        self.master.undelete(path)
     }
 
-    pub fn read_full(&self, path: &str, network: Arc<Mutex<NetworkShim>>) -> Vec<u8> {
-        // 1. Get the file metadata from the master.
-        let metadata = self.master.lock().unwrap().stat(path);
+    /// Atomically rename a file or directory. If `overwrite` is set, whatever is at `to` is
+    /// replaced, otherwise the rename fails if `to` exists.
+    pub fn rename(&self, from: &str, to: &str, overwrite: bool) -> Result<(), MasterError> {
+        self.master.rename(from, to, overwrite)
+    }
 
-        // 2. Begin reading the file in chunks.
-        let mut data = vec![];
-        let mut offset = 0;
+    /// Create a directory, and any missing parent directories.
+    pub fn mkdir(&self, path: &str) -> Result<(), MasterError> {
+        self.master.mkdir(path)
+    }
 
-        while offset < metadata.length {
-            // The length of the read is the minimum of the remaining file length and the chunk size.
-            // We read a single chunk at a time.
-            let length = std::cmp::min(metadata.length - offset, CHUNK_SIZE_BYTES as u64);
-            
-            // 3a. Get the chunk hash and locations from the master.
-            let read_info = self.master.lock().unwrap().get_read_infos(path, offset, length).unwrap();
+    /// Remove an empty directory.
+    pub fn rmdir(&self, path: &str) -> Result<(), MasterError> {
+        self.master.rmdir(path)
+    }
 
-            // Expect only one chunk_read.
-            assert_eq!(read_info.chunk_reads.len(), 1);
+    /// Copy a file or directory, sharing chunks with the original.
+    pub fn snapshot(&self, from: &str, to: &str) -> Result<(), MasterError> {
+        self.master.snapshot(from, to)
+    }
 
-            // 3b. Read the chunk from the chunkserver.
-            let chunk_read = &read_info.chunk_reads[0];
-            assert!(1 <= chunk_read.locations.len());
-            let location = &chunk_read.locations[0];
-            
-            let chunk_data = network.lock().unwrap().get_node(&location).unwrap().lock().unwrap().read_chunk(chunk_read.chunk_id).unwrap();
+    /// Read a whole file.
+    pub fn read_full(&self, path: &str, network: Arc<Mutex<dyn Transport>>) -> Result<Vec<u8>, ClientError> {
+        let metadata = self.master.stat(path).map_err(ClientError::Master)?;
+        self.read(path, 0, metadata.length, network)
+    }
 
-            // 4. Append the chunk data to the file data.
-            // If 
-            data.extend_from_slice(&chunk_data);
-            offset += length;
+    /// Read up to `length` bytes of a file from `offset`. Fewer bytes are returned if the
+    /// file ends first, and none if `offset` is the end of the file.
+    pub fn read(&self, path: &str, offset: u64, length: u64, network: Arc<Mutex<dyn Transport>>) -> Result<Vec<u8>, ClientError> {
+        // 1. Get the chunks covering the range, and their locations, from the master.
+        let read_info = self.master.get_read_infos(path, offset, length).map_err(ClientError::Master)?;
+
+        // 2. Read each chunk, trimmed to the range.
+        let mut data = Vec::with_capacity(read_info.length as usize);
+        for chunk_read in read_info.chunk_reads.iter() {
+            data.extend_from_slice(&read_chunk(&network, chunk_read).map_err(ClientError::Chunkserver)?);
         }
 
-        data
+        Ok(data)
     }
 
-    // pub fn read(&self, path: &str, offset: u64, length: u64, network: Arc<Mutex<NetworkShim>>) -> Vec<u8> {
-    // }
+    /// Open a file for appending, as a `GfsWriter`. The file is created on the first commit.
+    pub fn append_writer(&self, path: &str, network: Arc<Mutex<dyn Transport>>) -> GfsWriter {
+        GfsWriter {
+            master: self.master.clone(),
+            network,
+            path: path.to_string(),
+            buffer: Vec::with_capacity(CHUNK_SIZE_BYTES),
+            free_chunkservers: vec![],
+            chunks: vec![],
+            chunk_locations: HashMap::new(),
+            length: 0,
+            commit_failed: false,
+            push_window: self.push_window,
+        }
+    }
+
+    /// Open a file for reading, as a `GfsFile`.
+    pub fn open(&self, path: &str, network: Arc<Mutex<dyn Transport>>) -> Result<GfsFile, ClientError> {
+        let mut file = GfsFile {
+            master: self.master.clone(),
+            network,
+            path: path.to_string(),
+            length: 0,
+            chunks: vec![],
+            pos: 0,
+            buffer_start: 0,
+            buffer: vec![],
+        };
+        file.fetch_chunks().map_err(ClientError::Master)?;
+        Ok(file)
+    }
 
     /// Append data to a file.
-    pub fn append(&self, path: &str, data: &[u8], network: Arc<Mutex<NetworkShim>>) -> Result<(), ClientError> {
+    ///
+    /// Every chunk is pushed before any is committed, and the append is committed as one
+    /// operation, so it lands in the file whole, or not at all.
+    pub fn append(&self, path: &str, data: &[u8], network: Arc<Mutex<dyn Transport>>) -> Result<(), ClientError> {
         let append_length = data.len() as u64;
 
         println!("writing data size={}", data.len());
@@ -98,34 +158,17 @@ impl Client {
         println!("Appending {} chunks to {path}", chunks.len());
 
         // 2. Ask master for free chunkservers.
-        let replication: u8 = 3;
-        let mut free_chunkservers = self.master.lock().unwrap().get_free_chunkservers(chunks.len() as u64, replication);
+        let replication = REPLICATION_FACTOR as u8;
+        let free_chunkservers = self.master.get_free_chunkservers(chunks.len() as u64, replication).map_err(ClientError::Master)?;
 
-        if replication < free_chunkservers.len() as u8 {
+        if free_chunkservers.len() < replication as usize {
             return Err(ClientError::NotEnoughChunkservers);
         }
 
         // 3. Push each chunk to chunkservers with replicas.
         let mut chunk_locations: HashMap<ChunkHash, Vec<String>> = HashMap::new();
-
-        for (i, chunk) in chunks.iter().enumerate() {
-            for _ in 0..replication {
-                // Select the next chunkserver.
-                // Index into the free_chunkservers vector (round-robin).
-                let index = i * replication as usize;
-                let chunkserver_address = &free_chunkservers[index];
-
-                // Write the chunk to the chunkserver.
-                let chunkserver = network.lock().unwrap().get_node(chunkserver_address).unwrap();
-                chunkserver.lock().unwrap().push_chunk(&chunk.data).unwrap();
-
-                // Append the chunkserver to the list of chunk locations.
-                chunk_locations
-                    .entry(chunk.hash)
-                    .or_default()
-                    .push(chunkserver_address.clone());
-            }
-        }
+        push_chunks(&network, &chunks, &chunk_placements(&chunks), &free_chunkservers, self.push_window, &mut chunk_locations)
+            .map_err(ClientError::Chunkserver)?;
 
         // 4. Commit the chunks at the master.
         let op = AppendOperation {
@@ -134,8 +177,330 @@ impl Client {
             chunk_sequence: chunks.iter().map(|chunk| chunk.hash).collect(),
             chunk_locations,
         };
-        self.master.lock().unwrap().append_file(op).unwrap();
+        self.master.append_file(op).map_err(ClientError::Master)
+    }
+}
+
+/// The most chunks a `GfsWriter` pushes before committing them, so a long stream isn't all
+/// held by chunkservers waiting to be committed.
+pub const COMMIT_BATCH_CHUNKS: usize = 64;
+
+/// Where to place the replicas of each chunk of an append, as the index of the first chunk
+/// with the same data. See `replica_pushes`.
+///
+/// The master commits a chunk on every chunkserver its data was pushed to, so a repeated chunk
+/// is placed with its first copy, and each chunkserver is pushed it once for each commit.
+pub(crate) fn chunk_placements(chunks: &[ProtoChunk]) -> Vec<usize> {
+    let mut first: HashMap<ChunkHash, usize> = HashMap::new();
+    chunks.iter().enumerate().map(|(i, chunk)| *first.entry(chunk.hash).or_insert(i)).collect()
+}
+
+/// The pushes of chunks to `REPLICATION_FACTOR` chunkservers each, as the index of the chunk,
+/// and the index into the free_chunkservers vector of the chunkserver (round-robin).
+///
+/// The replicas of the `i`th chunk go to the chunkservers round-robin from the `placements[i]`th.
+pub(crate) fn replica_pushes(placements: &[usize], num_free: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let replication = REPLICATION_FACTOR;
+    placements.iter().enumerate().flat_map(move |(i, placement)| {
+        (0..replication).map(move |r| (i, (placement * replication + r) % num_free))
+    })
+}
+
+/// Push chunks to `REPLICATION_FACTOR` chunkservers each, with up to `window` pushes in
+/// flight at once, recording where each was pushed. See `replica_pushes`.
+fn push_chunks(network: &Arc<Mutex<dyn Transport>>, chunks: &[ProtoChunk], placements: &[usize], free_chunkservers: &[String], window: usize, chunk_locations: &mut HashMap<ChunkHash, Vec<String>>) -> Result<(), ChunkserverError> {
+    let replication = REPLICATION_FACTOR;
+    if chunks.is_empty() {
+        return Ok(());
+    }
+
+    // Look up each chunkserver once, rather than locking the network for every push.
+    let chunkservers: Vec<Option<Arc<dyn ChunkserverHandle>>> = {
+        let network = network.lock().unwrap();
+        free_chunkservers.iter().map(|x| network.get_node(x)).collect()
+    };
+
+    let pushes = Mutex::new(replica_pushes(placements, free_chunkservers.len()));
+    let pushed = Mutex::new(vec![]);
+
+    // Each worker pushes one chunk at a time, so at most `window` are in flight.
+    let workers = std::cmp::min(window.max(1), chunks.len() * replication);
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..workers).map(|_| scope.spawn(|| -> Result<(), ChunkserverError> {
+            loop {
+                let Some((i, index)) = pushes.lock().unwrap().next() else { return Ok(()) };
+                let chunkserver = chunkservers[index].as_ref().ok_or(ChunkserverError::Unavailable)?;
+                chunkserver.push_chunk(&chunks[i].data)?;
+                pushed.lock().unwrap().push((i, index));
+            }
+        })).collect();
+        workers.into_iter().try_for_each(|worker| worker.join().unwrap())
+    })?;
+
+    // Append the chunkservers to the lists of chunk locations.
+    for (i, index) in pushed.into_inner().unwrap() {
+        add_chunk_location(chunk_locations, chunks[i].hash, &free_chunkservers[index]);
+    }
+    Ok(())
+}
+
+/// Record that a chunk was pushed to a chunkserver. A repeated chunk is listed once, as the
+/// master commits every copy of it at each of its locations.
+pub(crate) fn add_chunk_location(chunk_locations: &mut HashMap<ChunkHash, Vec<String>>, chunk_hash: ChunkHash, location: &str) {
+    let locations = chunk_locations.entry(chunk_hash).or_default();
+    if !locations.iter().any(|x| x == location) {
+        locations.push(location.to_string());
+    }
+}
+
+/// Read the range of a chunk in a `ChunkRead`, from the first replica which responds.
+///
+/// A replica which is unreachable, whose data fails its checksums, or which returns too little
+/// data, is skipped.
+pub(crate) fn read_chunk(network: &Arc<Mutex<dyn Transport>>, chunk_read: &ChunkRead) -> Result<Vec<u8>, ChunkserverError> {
+    let mut res = Err(ChunkserverError::Unavailable);
+    for location in chunk_read.locations.iter() {
+        let chunkserver = network.lock().unwrap().get_node(location);
+        res = chunkserver
+            .ok_or(ChunkserverError::Unavailable)
+            .and_then(|chunkserver| chunkserver.read_chunk(chunk_read.chunk_id))
+            .and_then(|chunk_data| chunk_range(chunk_read, &chunk_data));
+        if res.is_ok() {
+            break;
+        }
+    }
+    res
+}
+
+/// The range of a chunk's data in a `ChunkRead`, or an error if the chunkserver returned
+/// too little data to cover it.
+pub(crate) fn chunk_range(chunk_read: &ChunkRead, chunk_data: &[u8]) -> Result<Vec<u8>, ChunkserverError> {
+    let start = chunk_read.offset as usize;
+    let range = chunk_data.get(start..start + chunk_read.length as usize).ok_or(ChunkserverError::InvalidChunkLength)?;
+    Ok(range.to_vec())
+}
+
+//
+// File handles.
+//
+
+/// A file opened for reading, with `std::io::Read`, `Seek` and `BufRead`.
+///
+/// The chunk locations are fetched when the file is opened, and one chunk is buffered at a
+/// time. Reading at the end of the file checks whether it has grown, so it can be tailed.
+pub struct GfsFile {
+    master: Arc<dyn MasterHandle>,
+    network: Arc<Mutex<dyn Transport>>,
+    path: String,
+    /// The length of the file, as of the last fetch of its chunks.
+    length: u64,
+    /// The chunks of the file, and the offset of each in the file.
+    chunks: Vec<(u64, ChunkRead)>,
+    pos: u64,
+    /// The offset in the file of the buffered chunk data.
+    buffer_start: u64,
+    buffer: Vec<u8>,
+}
+
+impl GfsFile {
+    /// The length of the file, as of when it was opened or last read to the end.
+    pub fn len(&self) -> u64 {
+        self.length
+    }
 
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Fetch the chunks appended since the file was opened, or since the last fetch.
+    fn fetch_chunks(&mut self) -> Result<(), MasterError> {
+        let read_info = self.master.get_read_infos(&self.path, self.length, u64::MAX)?;
+        let mut offset = self.length;
+        for chunk_read in read_info.chunk_reads {
+            let length = chunk_read.length;
+            self.chunks.push((offset, chunk_read));
+            offset += length;
+        }
+        self.length = offset;
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Buffer the chunk holding `pos`, returning false at the end of the file.
+    fn fill_buffer(&mut self) -> std::io::Result<bool> {
+        if self.pos >= self.length {
+            self.fetch_chunks().map_err(|err| std::io::Error::other(format!("{err:?}")))?;
+            if self.pos >= self.length {
+                return Ok(false);
+            }
+        }
+        let i = self.chunks.partition_point(|(offset, _)| *offset <= self.pos) - 1;
+        let (offset, chunk_read) = &self.chunks[i];
+        self.buffer = read_chunk(&self.network, chunk_read).map_err(|err| std::io::Error::other(format!("{err:?}")))?;
+        self.buffer_start = *offset;
+        Ok(true)
+    }
+}
+
+impl std::io::BufRead for GfsFile {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        let buffered = self.buffer_start..self.buffer_start + self.buffer.len() as u64;
+        if !buffered.contains(&self.pos) && !self.fill_buffer()? {
+            return Ok(&[]);
+        }
+        Ok(&self.buffer[(self.pos - self.buffer_start) as usize..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+    }
+}
+
+impl std::io::Read for GfsFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::io::BufRead;
+        let data = self.fill_buf()?;
+        let n = std::cmp::min(buf.len(), data.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl std::io::Seek for GfsFile {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            std::io::SeekFrom::Start(offset) => Some(offset),
+            std::io::SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            std::io::SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        let Some(pos) = pos else {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start of the file"));
+        };
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+/// A file opened for appending, with `std::io::Write`, so data can be streamed in.
+///
+/// Data is split into chunks, and each chunk is pushed to chunkservers once it is full.
+/// Every `COMMIT_BATCH_CHUNKS` chunks are committed as one append, so other appends to the
+/// file may land between batches, unlike with `Client::append`. `flush` commits whatever is buffered, padding the last
+/// chunk, and is called when the writer is closed or dropped.
+///
+/// Nothing written is lost to an error: a batch which fails to commit is kept, and pushed and
+/// committed again by the next write or flush.
+pub struct GfsWriter {
+    master: Arc<dyn MasterHandle>,
+    network: Arc<Mutex<dyn Transport>>,
+    path: String,
+    /// Data not yet pushed, less than a chunk.
+    buffer: Vec<u8>,
+    /// The chunkservers the current batch is pushed to.
+    free_chunkservers: Vec<String>,
+    /// The chunks pushed but not yet committed, kept until they are in case they must be pushed again.
+    chunks: Vec<ProtoChunk>,
+    chunk_locations: HashMap<ChunkHash, Vec<String>>,
+    /// The number of bytes pushed but not yet committed.
+    length: u64,
+    /// Whether committing the chunks failed. Some of the pushes may have been used up.
+    commit_failed: bool,
+    push_window: usize,
+}
+
+impl GfsWriter {
+    /// Flush the writer, committing everything written.
+    pub fn close(mut self) -> std::io::Result<()> {
+        use std::io::Write;
+        self.flush()
+    }
+
+    /// Pick the chunkservers to push a batch to.
+    fn get_free_chunkservers(&mut self) -> std::io::Result<()> {
+        self.free_chunkservers = self.master.get_free_chunkservers(COMMIT_BATCH_CHUNKS as u64, REPLICATION_FACTOR as u8)
+            .map_err(|err| std::io::Error::other(format!("{err:?}")))?;
+        if self.free_chunkservers.len() < REPLICATION_FACTOR {
+            return Err(std::io::Error::other(format!("{:?}", ClientError::NotEnoughChunkservers)));
+        }
+        Ok(())
+    }
+
+    /// Push the buffered data as a chunk. The buffer is only cleared once it is pushed.
+    fn push_buffer(&mut self) -> std::io::Result<()> {
+        let chunk = data_to_chunks(&self.buffer).pop().unwrap();
+        if self.chunks.is_empty() {
+            self.get_free_chunkservers()?;
+        }
+
+        // A repeated chunk is placed with its first copy in the batch. See `chunk_placements`.
+        let placement = self.chunks.iter().position(|x| x.hash == chunk.hash).unwrap_or(self.chunks.len());
+        push_chunks(&self.network, std::slice::from_ref(&chunk), &[placement], &self.free_chunkservers, self.push_window, &mut self.chunk_locations)
+            .map_err(|err| std::io::Error::other(format!("{err:?}")))?;
+        self.chunks.push(chunk);
+        self.length += self.buffer.len() as u64;
+        self.buffer.clear();
+
+        if self.chunks.len() >= COMMIT_BATCH_CHUNKS {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Commit the pushed chunks at the master. They are only dropped once committed.
+    fn commit(&mut self) -> std::io::Result<()> {
+        if self.chunks.is_empty() {
+            return Ok(());
+        }
+
+        // A failed commit may have used up pushes, so the batch is pushed again.
+        if self.commit_failed {
+            self.get_free_chunkservers()?;
+            self.chunk_locations.clear();
+            push_chunks(&self.network, &self.chunks, &chunk_placements(&self.chunks), &self.free_chunkservers, self.push_window, &mut self.chunk_locations)
+                .map_err(|err| std::io::Error::other(format!("{err:?}")))?;
+            self.commit_failed = false;
+        }
+
+        let op = AppendOperation {
+            file_path: self.path.clone(),
+            length: self.length,
+            chunk_sequence: self.chunks.iter().map(|chunk| chunk.hash).collect(),
+            chunk_locations: self.chunk_locations.clone(),
+        };
+        if let Err(err) = self.master.append_file(op) {
+            self.commit_failed = true;
+            return Err(std::io::Error::other(format!("{err:?}")));
+        }
+        self.chunks.clear();
+        self.chunk_locations.clear();
+        self.length = 0;
+        Ok(())
+    }
+}
+
+impl std::io::Write for GfsWriter {
+    /// Buffer data, pushing the buffer once it is a full chunk. A full buffer left by a failed
+    /// push is pushed before anything more is taken, so an error means nothing was taken.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.buffer.len() == CHUNK_SIZE_BYTES {
+            self.push_buffer()?;
+        }
+        let n = std::cmp::min(buf.len(), CHUNK_SIZE_BYTES - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.push_buffer()?;
+        }
+        self.commit()
+    }
+}
+
+impl Drop for GfsWriter {
+    fn drop(&mut self) {
+        use std::io::Write;
+        let _ = self.flush();
+    }
+}
@@ -1,7 +1,10 @@
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use sha2::Digest;
-use crate::chunkserver::Chunkserver;
+use crate::chunk::ChunkHash;
+use crate::chunkserver::{Chunkserver, ChunkserverError};
+use crate::master::{*};
 
 pub fn sha256sum(data: &[u8]) -> [u8; 32] {
     let mut hasher = sha2::Sha256::new();
@@ -12,15 +15,147 @@ pub fn sha256sum(data: &[u8]) -> [u8; 32] {
     result
 }
 
+/// A chunkserver, either in this process or reached over the network.
+pub trait ChunkserverHandle: Send + Sync {
+    fn push_chunk(&self, data: &[u8]) -> Result<(), ChunkserverError>;
+    fn commit_chunk(&self, chunk_hash: ChunkHash, chunk_id: u64, version: u64) -> Result<(), ChunkserverError>;
+    fn read_chunk(&self, chunk_id: u64) -> Result<Vec<u8>, ChunkserverError>;
+    fn replicate_chunk(&self, chunk_id: u64, version: u64, source: &str) -> Result<(), ChunkserverError>;
+    fn update_chunk_version(&self, chunk_id: u64, version: u64) -> Result<(), ChunkserverError>;
+}
+
+/// The master, either in this process or reached over the network.
+pub trait MasterHandle: Send + Sync {
+    fn receive_heartbeat(&self, heartbeat: Heartbeat) -> HeartbeatResponse;
+    fn append_file(&self, op: AppendOperation) -> Result<(), MasterError>;
+    fn get_free_chunkservers(&self, num_chunks: u64, replication_factor: u8) -> Result<Vec<String>, MasterError>;
+    fn get_read_infos(&self, path: &str, offset: u64, length: u64) -> Result<ReadOperationInfo, MasterError>;
+    fn stat(&self, path: &str) -> Result<StatInfo, MasterError>;
+    fn ls(&self, path: &str) -> Result<Vec<String>, MasterError>;
+    fn ls_tree(&self, path: &str) -> Result<Vec<String>, MasterError>;
+    fn df(&self) -> Result<u64, MasterError>;
+    fn du(&self) -> Result<u64, MasterError>;
+    fn delete(&self, path: &str) -> Result<(), MasterError>;
+    fn undelete(&self, path: &str) -> Result<(), MasterError>;
+    fn rename(&self, from: &str, to: &str, overwrite: bool) -> Result<(), MasterError>;
+    fn mkdir(&self, path: &str) -> Result<(), MasterError>;
+    fn rmdir(&self, path: &str) -> Result<(), MasterError>;
+    fn snapshot(&self, from: &str, to: &str) -> Result<(), MasterError>;
+}
+
+/// Locates chunkservers by their ID.
+pub trait Transport: Send {
+    fn get_node(&self, id: &str) -> Option<Arc<dyn ChunkserverHandle>>;
+
+    /// Called by the master when a chunkserver registers by heartbeat, for transports which
+    /// only reach registered chunkservers.
+    fn register_node(&mut self, _id: &str) {}
+}
+
+impl ChunkserverHandle for Mutex<Chunkserver> {
+    fn push_chunk(&self, data: &[u8]) -> Result<(), ChunkserverError> {
+        self.lock().unwrap().push_chunk(data)
+    }
+
+    fn commit_chunk(&self, chunk_hash: ChunkHash, chunk_id: u64, version: u64) -> Result<(), ChunkserverError> {
+        self.lock().unwrap().commit_chunk(chunk_hash, chunk_id, version)
+    }
+
+    fn read_chunk(&self, chunk_id: u64) -> Result<Vec<u8>, ChunkserverError> {
+        self.lock().unwrap().read_chunk(chunk_id)
+    }
+
+    fn replicate_chunk(&self, chunk_id: u64, version: u64, source: &str) -> Result<(), ChunkserverError> {
+        self.lock().unwrap().replicate_chunk(chunk_id, version, source)
+    }
+
+    fn update_chunk_version(&self, chunk_id: u64, version: u64) -> Result<(), ChunkserverError> {
+        self.lock().unwrap().update_chunk_version(chunk_id, version)
+    }
+}
+
+impl MasterHandle for Mutex<MasterServer> {
+    fn receive_heartbeat(&self, heartbeat: Heartbeat) -> HeartbeatResponse {
+        self.lock().unwrap().receive_heartbeat(heartbeat)
+    }
+
+    fn append_file(&self, op: AppendOperation) -> Result<(), MasterError> {
+        MasterServer::append_file(self, op)
+    }
+
+    fn get_free_chunkservers(&self, num_chunks: u64, replication_factor: u8) -> Result<Vec<String>, MasterError> {
+        Ok(self.lock().unwrap().get_free_chunkservers(num_chunks, replication_factor))
+    }
+
+    fn get_read_infos(&self, path: &str, offset: u64, length: u64) -> Result<ReadOperationInfo, MasterError> {
+        let _locks = MasterServer::lock_namespace(self, &[path], &[]);
+        self.lock().unwrap().get_read_infos(path, offset, length)
+    }
+
+    fn stat(&self, path: &str) -> Result<StatInfo, MasterError> {
+        let _locks = MasterServer::lock_namespace(self, &[path], &[]);
+        self.lock().unwrap().stat(path)
+    }
+
+    fn ls(&self, path: &str) -> Result<Vec<String>, MasterError> {
+        let _locks = MasterServer::lock_namespace(self, &[path], &[]);
+        Ok(self.lock().unwrap().ls(path))
+    }
+
+    fn ls_tree(&self, path: &str) -> Result<Vec<String>, MasterError> {
+        let _locks = MasterServer::lock_namespace(self, &[path], &[]);
+        Ok(self.lock().unwrap().ls_tree(path))
+    }
+
+    fn df(&self) -> Result<u64, MasterError> {
+        Ok(self.lock().unwrap().df())
+    }
+
+    fn du(&self) -> Result<u64, MasterError> {
+        Ok(self.lock().unwrap().du())
+    }
+
+    fn delete(&self, path: &str) -> Result<(), MasterError> {
+        let _locks = MasterServer::lock_namespace(self, &[], &[path]);
+        self.lock().unwrap().delete(path)
+    }
+
+    fn undelete(&self, path: &str) -> Result<(), MasterError> {
+        let _locks = MasterServer::lock_namespace(self, &[], &[path]);
+        self.lock().unwrap().undelete(path)
+    }
+
+    fn rename(&self, from: &str, to: &str, overwrite: bool) -> Result<(), MasterError> {
+        let _locks = MasterServer::lock_namespace(self, &[], &[from, to]);
+        self.lock().unwrap().rename(from, to, overwrite)
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), MasterError> {
+        let _locks = MasterServer::lock_namespace(self, &[], &[path]);
+        self.lock().unwrap().mkdir(path)
+    }
+
+    fn rmdir(&self, path: &str) -> Result<(), MasterError> {
+        let _locks = MasterServer::lock_namespace(self, &[], &[path]);
+        self.lock().unwrap().rmdir(path)
+    }
+
+    fn snapshot(&self, from: &str, to: &str) -> Result<(), MasterError> {
+        // Appends to the source are finished before the snapshot is taken.
+        let _locks = MasterServer::lock_namespace(self, &[], &[from, to]);
+        MasterServer::snapshot(self, from, to)
+    }
+}
+
+/// An in-memory transport, for running a whole cluster in one process.
+#[derive(Default)]
 pub struct NetworkShim {
     nodes: HashMap<String, Arc<Mutex<Chunkserver>>>,
 }
 
 impl NetworkShim {
     pub fn new() -> NetworkShim {
-        NetworkShim {
-            nodes: HashMap::new(),
-        }
+        NetworkShim::default()
     }
 
     pub fn add_node(&mut self, chunkserver: Arc<Mutex<Chunkserver>>) {
@@ -28,11 +163,15 @@ impl NetworkShim {
         let id = cs.id.clone();
         self.nodes.insert(id, chunkserver.clone());
     }
+}
 
-    pub fn get_node(&self, id: &str) -> Option<Arc<Mutex<Chunkserver>>> {
-        match self.nodes.get(id) {
-            Some(chunkserver) => Some(chunkserver.clone()),
-            None => None,
-        }
+impl Transport for NetworkShim {
+    fn get_node(&self, id: &str) -> Option<Arc<dyn ChunkserverHandle>> {
+        self.nodes.get(id).map(|chunkserver| chunkserver.clone() as Arc<dyn ChunkserverHandle>)
     }
-}
\ No newline at end of file
+}
+
+/// Flush a directory's entries, such as renames into it, to disk.
+pub(crate) fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::File::open(dir)?.sync_all()
+}
@@ -2,4 +2,10 @@ pub mod master;
 pub mod chunkserver;
 pub mod common;
 pub mod client;
-pub mod chunk;
\ No newline at end of file
+pub mod async_client;
+pub mod chunk;
+pub mod rpc;
+pub mod proto;
+pub mod oplog;
+pub mod namespace;
+pub mod packed;
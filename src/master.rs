@@ -1,13 +1,19 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use serde::{Serialize, Deserialize};
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::vec;
 use crate::chunk::ChunkHash;
-use crate::chunkserver::{*};
+use crate::chunkserver::ChunkserverError;
 use crate::common::{*};
 use crate::chunk::{*};
+use crate::oplog::{*};
+use crate::namespace::{*};
 
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,6 +22,15 @@ pub enum MasterError {
     EndOfFile,
     ChunkNotFound,
     ChunkserverNotFound,
+    FileExists,
+    InvalidPath,
+    DirectoryNotEmpty,
+    /// The operation log couldn't be written, so nothing was changed.
+    LogFailed,
+    /// The master failed with an error this node doesn't know of, from a newer version.
+    Unavailable,
+    /// A chunk of the append couldn't be committed to any chunkserver, so nothing was appended.
+    AppendFailed,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -24,45 +39,152 @@ pub struct File {
     pub length: u64,
     /// The chunks that make up the file.
     pub chunks: Vec<u64>,
+    /// The offset in the file of each chunk. Appends pad their last chunk, so chunks
+    /// can hold less than `CHUNK_SIZE_BYTES` of the file.
+    #[serde(default)]
+    pub chunk_offsets: Vec<u64>,
 }
 
+impl File {
+    /// Record the chunks of an append of `length` bytes. Every chunk is full, but the last.
+    fn append(&mut self, chunks: &[u64], length: u64) {
+        for i in 0..chunks.len() as u64 {
+            self.chunk_offsets.push(self.length + i * CHUNK_SIZE_BYTES as u64);
+        }
+        self.chunks.extend(chunks);
+        self.length += length;
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct StatInfo {
     /// The length of the file in bytes.
     pub length: u64,
 }
 
+/// How often chunkservers send heartbeats to the master.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The number of heartbeats a chunkserver can miss before the master considers it dead.
+pub const MAX_MISSED_HEARTBEATS: u32 = 3;
+
+/// The number of replicas the master keeps of each chunk.
+pub const REPLICATION_FACTOR: usize = 3;
+
+/// The most chunks re-replicated per heartbeat interval, so foreground traffic isn't starved.
+pub const MAX_REPLICATIONS_PER_TICK: usize = 2;
+
+/// How often the master looks for chunks which no file references.
+pub const GARBAGE_COLLECTION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a deleted file can be undeleted for, by default.
+pub const DEFAULT_DELETION_GRACE_PERIOD: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+/// Deleted files are renamed to a hidden name, `.deleted.{unix millis}.{name}`, in the same directory.
+const DELETED_PREFIX: &str = ".deleted.";
+
+fn deleted_name(path: &str, deleted_at: u64) -> String {
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    format!("{dir}/{DELETED_PREFIX}{deleted_at}.{name}")
+}
+
+/// Parse a hidden name into the path of the deleted file and when it was deleted.
+fn parse_deleted_name(path: &str) -> Option<(String, u64)> {
+    let (dir, name) = path.rsplit_once('/')?;
+    let (deleted_at, name) = name.strip_prefix(DELETED_PREFIX)?.split_once('.')?;
+    Some((format!("{dir}/{name}"), deleted_at.parse().ok()?))
+}
+
+/// Whether a path has a component in the form of a hidden name, which clients can't create.
+fn is_reserved(path: &str) -> bool {
+    path.split('/').any(|name| name.starts_with(DELETED_PREFIX))
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
 #[allow(dead_code)]
 struct ChunkserverInfo {
     id: String,
-    last_seen: u64,
+    /// When the last heartbeat was received.
+    last_seen: Instant,
+    /// Whether the chunkserver was alive at the last liveness check.
+    alive: bool,
     ip: String,
     port: u16,
-    chunks: Vec<u64>,
+    /// The chunks the chunkserver has reported holding.
+    chunks: HashSet<u64>,
+    /// Chunks the chunkserver should delete, sent with the next heartbeat reply.
+    /// See `HeartbeatResponse::delete_chunks`.
+    garbage: HashMap<u64, u64>,
+    /// Whether to ask for a full report in the next heartbeat reply, as replicas the
+    /// chunkserver holds may have gone stale.
+    full_report_needed: bool,
     disk_used: u64,
     disk_free: u64,
 }
 
+impl ChunkserverInfo {
+    fn is_alive(&self) -> bool {
+        self.last_seen.elapsed() <= HEARTBEAT_INTERVAL * MAX_MISSED_HEARTBEATS
+    }
+}
+
+
+/// Identifies a binary checkpoint file, and its format version.
+///
+/// bincode isn't self-describing, so `#[serde(default)]` has no effect on checkpoints: bump
+/// this whenever the layout of `MasterServerState` changes.
+const CHECKPOINT_MAGIC: &[u8; 8] = b"GFSCKPT1";
 
 /// The persistent state of the master server.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MasterServerState {
     file_table: HashMap<String, File>,
+    /// The directory tree, indexing the paths in `file_table` and any empty directories.
+    #[serde(default)]
+    namespace: Namespace,
     chunk_counter: u64,
+    /// The version of each chunk, incremented whenever a mutation is granted on it: when it is
+    /// written, re-replicated or snapshotted. Replicas with an older version missed a mutation,
+    /// and are stale.
+    #[serde(default)]
+    chunk_versions: HashMap<u64, u64>,
+    /// The number of references to each chunk from files. Derived from `file_table`.
+    #[serde(skip)]
+    chunk_refs: HashMap<u64, u64>,
+    /// The sequence number of the last operation log entry applied to this state.
+    #[serde(default)]
+    log_seq: u64,
+}
+
+impl Default for MasterServerState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MasterServerState {
     pub fn new() -> MasterServerState {
         MasterServerState {
             file_table: HashMap::new(),
+            namespace: Namespace::new(),
             chunk_counter: 0,
+            chunk_versions: HashMap::new(),
+            chunk_refs: HashMap::new(),
+            log_seq: 0,
         }
     }
 
-    pub fn from_file(path: PathBuf) -> MasterServerState {
+    pub fn from_file(path: PathBuf) -> std::io::Result<MasterServerState> {
         // Load the state from a file.
-        let file = std::fs::read_to_string(path).unwrap();
-        let state: MasterServerState = serde_json::from_str(&file).unwrap();
-        state
+        let file = std::fs::read_to_string(path)?;
+        let mut state: MasterServerState = serde_json::from_str(&file)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("corrupt state: {err}")))?;
+        state.index_files()?;
+        state.count_chunk_refs();
+        Ok(state)
     }
 
     pub fn to_file(&self, path: PathBuf) {
@@ -70,6 +192,198 @@ impl MasterServerState {
         let file = serde_json::to_string(self).unwrap();
         std::fs::write(path, file).unwrap();
     }
+
+    /// Load the state persisted in `dir`: the binary checkpoint if there is one,
+    /// otherwise a JSON state file, otherwise an empty state.
+    ///
+    /// Operation log entries after the checkpoint still need to be applied.
+    ///
+    /// An unreadable checkpoint or state file is an error, rather than falling back to the
+    /// operation log, as the log entries folded into it have been removed.
+    pub fn load(dir: &Path) -> std::io::Result<MasterServerState> {
+        use bincode::Options;
+        let checkpoint_path = dir.join("checkpoint");
+        let state_path = dir.join("state");
+        if checkpoint_path.try_exists().unwrap_or(false) {
+            let data = std::fs::read(checkpoint_path)?;
+            let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+            let data = data.strip_prefix(CHECKPOINT_MAGIC).ok_or_else(|| invalid("unrecognised checkpoint format"))?;
+            // Rejected unless it is exactly one state.
+            let mut state: MasterServerState = bincode::DefaultOptions::new().with_fixint_encoding().deserialize(data)
+                .map_err(|err| invalid(&format!("corrupt checkpoint: {err}")))?;
+            state.index_files()?;
+            state.count_chunk_refs();
+            Ok(state)
+        } else if state_path.try_exists().unwrap_or(false) {
+            MasterServerState::from_file(state_path)
+        } else {
+            Ok(MasterServerState::new())
+        }
+    }
+
+    /// Atomically replace the checkpoint in `dir` with this state.
+    pub fn write_checkpoint(&self, dir: &Path) -> std::io::Result<()> {
+        let tmp_path = dir.join("checkpoint.tmp");
+        let mut data = CHECKPOINT_MAGIC.to_vec();
+        bincode::serialize_into(&mut data, self).map_err(std::io::Error::other)?;
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, dir.join("checkpoint"))?;
+        sync_dir(dir)
+    }
+
+    /// Apply a logged mutation. Entries already reflected in this state are skipped.
+    pub fn apply(&mut self, entry: &LogEntry) {
+        if entry.seq <= self.log_seq {
+            return;
+        }
+        self.log_seq = entry.seq;
+
+        match &entry.record {
+            LogRecord::CreateFile { path } => {
+                self.file_table.entry(path.clone()).or_default();
+                self.namespace.insert(path, Entry::File);
+            }
+            LogRecord::AllocateChunks { next_chunk_id } => {
+                self.chunk_counter = std::cmp::max(self.chunk_counter, *next_chunk_id);
+            }
+            LogRecord::AppendChunks { path, chunks, length } => {
+                if self.namespace.get(path).is_none() {
+                    self.namespace.insert(path, Entry::File);
+                }
+                self.file_table.entry(path.clone()).or_default().append(chunks, *length);
+                for chunk_id in chunks {
+                    *self.chunk_refs.entry(*chunk_id).or_default() += 1;
+                }
+            }
+            LogRecord::RenameFile { from, to } => self.rename_file(from, to),
+            LogRecord::Rename { from, to, replaced } => {
+                // Renaming a path to its own ancestor would remove the path along with the ancestor.
+                if from.starts_with(&format!("{to}/")) {
+                    return;
+                }
+                if let Some(replaced) = replaced {
+                    self.rename_file(to, replaced);
+                }
+                for path in self.namespace.files(to) {
+                    self.remove_file(&path);
+                }
+                self.namespace.remove(to);
+                let files = self.namespace.files(from);
+                if let Some(entry) = self.namespace.remove(from) {
+                    for path in files {
+                        if let Some(file) = self.file_table.remove(&path) {
+                            self.file_table.insert(format!("{to}{}", &path[from.len()..]), file);
+                        }
+                    }
+                    self.namespace.insert(to, entry);
+                }
+            }
+            LogRecord::Snapshot { from, to } => {
+                let Some(entry) = self.namespace.get(from).cloned() else { return };
+                for path in self.namespace.files(from) {
+                    let Some(file) = self.file_table.get(&path).cloned() else { continue };
+                    for chunk_id in file.chunks.iter() {
+                        *self.chunk_refs.entry(*chunk_id).or_default() += 1;
+                    }
+                    self.file_table.insert(format!("{to}{}", &path[from.len()..]), file);
+                }
+                self.namespace.insert(to, entry);
+            }
+            LogRecord::RemoveFile { path } => {
+                self.remove_file(path);
+            }
+            LogRecord::Mkdir { path } => {
+                if self.namespace.get(path).is_none() {
+                    self.namespace.insert(path, Entry::Directory(Default::default()));
+                }
+            }
+            LogRecord::Rmdir { path } => {
+                for path in self.namespace.files(path) {
+                    self.remove_file(&path);
+                }
+                self.namespace.remove(path);
+            }
+            LogRecord::BumpChunkVersions { chunks } => {
+                for chunk_id in chunks {
+                    *self.chunk_versions.entry(*chunk_id).or_default() += 1;
+                }
+            }
+            LogRecord::SetChunkVersion { chunk_id, version } => {
+                let current = self.chunk_versions.entry(*chunk_id).or_default();
+                *current = std::cmp::max(*current, *version);
+            }
+        }
+    }
+
+    fn rename_file(&mut self, from: &str, to: &str) {
+        if let Some(file) = self.file_table.remove(from) {
+            self.file_table.insert(to.to_string(), file);
+            self.namespace.remove(from);
+            self.namespace.insert(to, Entry::File);
+        }
+    }
+
+    fn remove_file(&mut self, path: &str) {
+        self.namespace.remove(path);
+        let chunks = self.file_table.remove(path).map(|file| file.chunks).unwrap_or_default();
+        for chunk_id in chunks {
+            let refs = self.chunk_refs.entry(chunk_id).or_default();
+            *refs -= 1;
+            if *refs == 0 {
+                self.chunk_refs.remove(&chunk_id);
+            }
+        }
+    }
+
+    /// Index the files of a state saved before the namespace and chunk offsets were persisted.
+    ///
+    /// Such a state may not have normalised its paths, or may have a file under another
+    /// file, e.g. both `/a` and `/a/b`. Paths which can't all be kept are an error.
+    fn index_files(&mut self) -> std::io::Result<()> {
+        let conflict = |path: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("conflicting path in file table: {path}"));
+        for (path, file) in std::mem::take(&mut self.file_table) {
+            if self.file_table.insert(normalize_path(&path), file).is_some() {
+                return Err(conflict(&path));
+            }
+        }
+        if self.namespace.is_empty() {
+            let mut paths: Vec<&String> = self.file_table.keys().collect();
+            paths.sort();
+            for path in paths {
+                if !self.namespace.can_create(path) || self.namespace.get(path).is_some() {
+                    return Err(conflict(path));
+                }
+                self.namespace.insert(path, Entry::File);
+            }
+        }
+        // Where appends padded their chunks wasn't recorded, so assume every chunk is full.
+        for file in self.file_table.values_mut().filter(|file| file.chunk_offsets.len() != file.chunks.len()) {
+            file.chunk_offsets = (0..file.chunks.len() as u64).map(|i| i * CHUNK_SIZE_BYTES as u64).collect();
+        }
+        Ok(())
+    }
+
+    fn count_chunk_refs(&mut self) {
+        self.chunk_refs.clear();
+        for chunk_id in self.file_table.values().flat_map(|file| file.chunks.iter()) {
+            *self.chunk_refs.entry(*chunk_id).or_default() += 1;
+        }
+    }
+
+    /// Whether a chunk can be deleted: it has been allocated, but no file references it.
+    ///
+    /// Chunks the master never allocated are kept, rather than risk deleting data the
+    /// master doesn't know about (e.g. it was started with an empty state directory).
+    fn is_garbage(&self, chunk_id: u64) -> bool {
+        chunk_id < self.chunk_counter && !self.chunk_refs.contains_key(&chunk_id)
+    }
+
+    /// The current version of a chunk.
+    fn chunk_version(&self, chunk_id: u64) -> u64 {
+        self.chunk_versions.get(&chunk_id).copied().unwrap_or(0)
+    }
 }
 
 pub struct MasterServer {
@@ -77,9 +391,59 @@ pub struct MasterServer {
 
     // Ephermal state.
     chunkservers: HashMap<String, ChunkserverInfo>,
+    /// The chunkservers holding an up-to-date replica of each chunk.
     chunk_locations: HashMap<u64, Vec<String>>,
 
-    network: Arc<Mutex<NetworkShim>>,
+    /// Chunks with fewer live replicas than `REPLICATION_FACTOR`, by the number missing.
+    replication_queue: BinaryHeap<(usize, Reverse<u64>)>,
+    /// The priority each chunk was last queued with. Queue entries that don't match are stale.
+    queued_replications: HashMap<u64, usize>,
+    started: Instant,
+    last_garbage_collection: Instant,
+    deletion_grace_period: Duration,
+
+    network: Arc<Mutex<dyn Transport>>,
+
+    namespace_locks: Arc<NamespaceLocks>,
+    /// Chunks allocated by appends which are committing them.
+    appending_chunks: HashSet<u64>,
+    /// Chunks whose versions are being bumped. See `begin_version_bumps`.
+    bumping_chunks: HashSet<u64>,
+
+    /// The operation log, if the master's state is persisted.
+    oplog: Option<OperationLog>,
+}
+
+/// An instruction for a chunkserver to copy a chunk from another.
+///
+/// Copying is granted as a mutation, so the chunk's live replicas are told its new version
+/// first. See `VersionBump`.
+#[derive(Debug, Clone)]
+pub struct Replication {
+    pub chunk_id: u64,
+    pub version: u64,
+    pub source: String,
+    pub destination: String,
+    /// The live replicas, including the source, to tell the new version.
+    pub replicas: Vec<String>,
+}
+
+impl Replication {
+    fn bump(&self) -> VersionBump {
+        VersionBump { chunk_id: self.chunk_id, version: self.version, replicas: self.replicas.clone() }
+    }
+}
+
+/// A new version of a chunk, granted along with a mutation.
+///
+/// The chunk's live replicas are told the new version before the master logs it, so if the
+/// master crashes in between, the replicas are ahead of it, rather than all being stale.
+#[derive(Debug, Clone)]
+pub struct VersionBump {
+    pub chunk_id: u64,
+    pub version: u64,
+    /// The live replicas, to tell the new version.
+    pub replicas: Vec<String>,
 }
 
 struct DiskStats {
@@ -88,11 +452,7 @@ struct DiskStats {
 }
 
 
-struct ChunkLocation {
-    chunk_id: u64,
-    chunkserver: String,
-}
-
+#[derive(Debug, Clone)]
 pub struct AppendOperation {
     /// The file path to append to.
     pub file_path: String,
@@ -107,11 +467,16 @@ pub struct AppendOperation {
     pub length: u64,
 }
 
+#[derive(Debug, Clone)]
 pub struct ChunkRead {
     pub chunk_id: u64,
     pub locations: Vec<String>,
+    /// The range of the chunk to read.
+    pub offset: u64,
+    pub length: u64,
 }
 
+#[derive(Debug, Clone)]
 pub struct ReadOperationInfo {
     pub path: String,
     pub offset: u64,
@@ -119,25 +484,156 @@ pub struct ReadOperationInfo {
     pub chunk_reads: Vec<ChunkRead>,
 }
 
+/// A status report sent by a chunkserver to the master.
+///
+/// Chunk locations aren't persisted by the master. Instead, a chunkserver reports every
+/// chunk it holds when it registers, and only the changes on later heartbeats.
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    pub chunkserver_id: String,
+    pub disk_used: u64,
+    /// The bytes the chunkserver can still store: the rest of its allocation, or the free
+    /// space on its disk if that is less.
+    pub disk_free: u64,
+    /// Whether `added_chunks` lists every chunk held, rather than changes since the last heartbeat.
+    pub full_report: bool,
+    /// Chunk ID to version.
+    pub added_chunks: HashMap<u64, u64>,
+    pub removed_chunks: Vec<u64>,
+    /// Chunks whose replicas failed their checksums, and were dropped.
+    pub corrupt_chunks: Vec<u64>,
+}
+
+/// The master's reply to a heartbeat.
+#[derive(Debug, Clone)]
+pub struct HeartbeatResponse {
+    /// The master doesn't know which chunks the chunkserver holds (e.g. because it restarted),
+    /// so the next heartbeat should be a full report.
+    pub full_report_needed: bool,
+    /// Chunks the chunkserver should delete, by ID, and the newest version to delete.
+    /// A newer replica, e.g. one re-replicated since the delete was issued, is kept.
+    pub delete_chunks: HashMap<u64, u64>,
+}
+
 impl MasterServer {
-    pub fn new(network: Arc<Mutex<NetworkShim>>, state: MasterServerState) -> MasterServer {
+    pub fn new(network: Arc<Mutex<dyn Transport>>, state: MasterServerState) -> MasterServer {
         MasterServer {
             state,
             chunkservers: HashMap::new(),
             network,
             chunk_locations: HashMap::new(),
+            replication_queue: BinaryHeap::new(),
+            queued_replications: HashMap::new(),
+            started: Instant::now(),
+            last_garbage_collection: Instant::now(),
+            deletion_grace_period: DEFAULT_DELETION_GRACE_PERIOD,
+            namespace_locks: Arc::new(NamespaceLocks::new()),
+            appending_chunks: HashSet::new(),
+            bumping_chunks: HashSet::new(),
+            oplog: None,
+        }
+    }
+
+    /// Open a master whose state is persisted in `dir`, recovering from the last
+    /// checkpoint and replaying the operation log (`dir/oplog.*`) on top.
+    pub fn open(network: Arc<Mutex<dyn Transport>>, dir: PathBuf) -> std::io::Result<MasterServer> {
+        std::fs::create_dir_all(&dir)?;
+
+        let mut state = MasterServerState::load(&dir)?;
+        let (oplog, entries) = OperationLog::open(dir)?;
+        entries.iter().for_each(|entry| state.apply(entry));
+        println!("[master] recovered {} files, replayed {} log entries", state.file_table.len(), entries.len());
+
+        let mut master = MasterServer::new(network, state);
+        master.oplog = Some(oplog);
+        Ok(master)
+    }
+
+    /// Set how long a deleted file can be undeleted for, before its chunks are reclaimed.
+    pub fn set_deletion_grace_period(&mut self, grace_period: Duration) {
+        self.deletion_grace_period = grace_period;
+    }
+
+    /// Seal the operation log, so everything logged so far can be folded into a checkpoint
+    /// by `write_checkpoint`. This only opens a new log segment, so mutations aren't held up.
+    ///
+    /// Returns the directory and the sealed segment, or `None` if there's nothing to checkpoint.
+    pub fn begin_checkpoint(&mut self) -> Option<(PathBuf, u64)> {
+        let oplog = self.oplog.as_mut()?;
+        let sealed = oplog.roll().unwrap()?;
+        Some((oplog.dir().to_path_buf(), sealed))
+    }
+
+    /// Durably log mutations and then apply them to the state.
+    fn log(&mut self, records: Vec<LogRecord>) -> Result<(), String> {
+        let entries: Vec<LogEntry> = records.into_iter().enumerate().map(|(i, record)| {
+            LogEntry { seq: self.state.log_seq + 1 + i as u64, record }
+        }).collect();
+
+        if let Some(oplog) = &mut self.oplog {
+            oplog.append(&entries).map_err(|err| format!("failed to write operation log: {err}"))?;
+        }
+        entries.iter().for_each(|entry| self.state.apply(entry));
+        Ok(())
+    }
+
+    /// Log mutations for a client operation, failing it if the log can't be written.
+    fn log_op(&mut self, records: Vec<LogRecord>) -> Result<(), MasterError> {
+        self.log(records).map_err(|err| {
+            println!("[master] {err}");
+            MasterError::LogFailed
+        })
+    }
+
+    /// Run the master's background tasks, forever.
+    pub fn run(master: Arc<Mutex<MasterServer>>) {
+        loop {
+            std::thread::sleep(HEARTBEAT_INTERVAL);
+            let (network, replications) = {
+                let mut master = master.lock().unwrap();
+                master.check_liveness();
+                if master.last_garbage_collection.elapsed() >= GARBAGE_COLLECTION_INTERVAL {
+                    master.collect_garbage();
+                }
+                (master.network.clone(), master.next_replications(MAX_REPLICATIONS_PER_TICK))
+            };
+
+            // Chunks are copied without holding the master lock.
+            for mut replication in replications {
+                let bump = replication.bump();
+                let updated = update_versions(&network, std::slice::from_ref(&bump));
+                if let Err(err) = master.lock().unwrap().finish_version_bumps(&[bump], &updated, vec![]) {
+                    println!("[master] failed to bump version of chunk {}: {err:?}", replication.chunk_id);
+                    continue;
+                }
+
+                // Copy from a replica which was told the new version.
+                let Replication { chunk_id, version, source, destination, .. } = &replication;
+                let told = |x: &String| updated.contains(&(*chunk_id, x.clone()));
+                let Some(source) = Some(source).filter(|x| told(x)).or_else(|| replication.replicas.iter().find(|x| told(x))).cloned() else {
+                    println!("[master] no replica of chunk {chunk_id} took its new version; not re-replicating");
+                    continue;
+                };
+                let chunkserver = network.lock().unwrap().get_node(destination);
+                let res = chunkserver
+                    .ok_or(ChunkserverError::Unavailable)
+                    .and_then(|chunkserver| chunkserver.replicate_chunk(*chunk_id, *version, &source));
+                replication.source = source;
+                master.lock().unwrap().finish_replication(replication, res);
+            }
         }
     }
 
-    pub fn run(&self) {
-        // Run the master server.
+    /// Whether a chunkserver is registered and has heartbeated recently.
+    fn is_alive(&self, chunkserver_id: &str) -> bool {
+        self.chunkservers.get(chunkserver_id).is_some_and(|x| x.is_alive())
     }
 
     fn compute_stats(&self) -> DiskStats {
         let mut disk_used = 0;
         let mut disk_free = 0;
 
-        self.chunkservers.iter().for_each(|(_, chunkserver_info)| {
+        self.chunkservers.values().filter(|x| x.is_alive()).for_each(|chunkserver_info| {
             disk_used += chunkserver_info.disk_used;
             disk_free += chunkserver_info.disk_free;
         });
@@ -146,135 +642,619 @@ impl MasterServer {
     }
 
     /// Get a list of chunkservers that have enough free storage space to store the chunks.
-    pub fn get_free_chunkservers(&self, _num_chunks: u64, replication_factor: u8) -> Vec<String> {
-        let mut chunkservers = self.chunkservers.values().collect::<Vec<&ChunkserverInfo>>();
+    pub fn get_free_chunkservers(&self, _num_chunks: u64, _replication_factor: u8) -> Vec<String> {
+        let mut chunkservers = self.chunkservers.values().filter(|x| x.is_alive()).collect::<Vec<&ChunkserverInfo>>();
         // Sort by disk free space and then map onto id
-        chunkservers.sort_by(|a, b| a.disk_free.cmp(&b.disk_free));
+        chunkservers.sort_by_key(|a| a.disk_free);
         chunkservers.into_iter().map(|x| x.id.clone()).collect()
     }
 
-    fn allocate_chunk(&mut self) -> u64 {
+    /// Allocate `n` consecutive chunk IDs, returning the first.
+    ///
+    /// Writing the chunks is granted as their first mutation, so they start at version 1.
+    fn allocate_chunks(&mut self, n: u64) -> Result<u64, MasterError> {
         let chunk_id = self.state.chunk_counter;
-        self.state.chunk_counter += 1;
-        chunk_id
+        self.log_op(vec![
+            LogRecord::AllocateChunks { next_chunk_id: chunk_id + n },
+            LogRecord::BumpChunkVersions { chunks: (chunk_id..chunk_id + n).collect() },
+        ])?;
+        Ok(chunk_id)
     }
 
 
+    /// The number of replicas a chunk is missing.
+    fn missing_replicas(&self, chunk_id: u64) -> usize {
+        if self.is_garbage(chunk_id) {
+            return 0;
+        }
+        let live = self.chunk_locations.get(&chunk_id).into_iter().flatten().filter(|x| self.is_alive(x)).count();
+        REPLICATION_FACTOR.saturating_sub(live)
+    }
+
+    /// Queue a chunk for re-replication if it is missing replicas.
+    fn enqueue_replication(&mut self, chunk_id: u64) {
+        let missing = self.missing_replicas(chunk_id);
+        if missing == 0 || self.queued_replications.get(&chunk_id) == Some(&missing) {
+            return;
+        }
+        self.queued_replications.insert(chunk_id, missing);
+        self.replication_queue.push((missing, Reverse(chunk_id)));
+    }
+
+    /// Take up to `limit` chunks off the re-replication queue, most missing replicas first,
+    /// and pick a surviving replica to copy each from and a chunkserver to copy it to.
+    pub fn next_replications(&mut self, limit: usize) -> Vec<Replication> {
+        // Until every chunkserver has had a chance to report its chunks, replicas would look missing.
+        if self.started.elapsed() < HEARTBEAT_INTERVAL * MAX_MISSED_HEARTBEATS {
+            return vec![];
+        }
+
+        let mut replications = vec![];
+        let mut deferred = vec![];
+        while replications.len() < limit {
+            let Some((priority, Reverse(chunk_id))) = self.replication_queue.pop() else { break };
+            if self.queued_replications.get(&chunk_id) != Some(&priority) {
+                continue;
+            }
+            self.queued_replications.remove(&chunk_id);
+
+            // Replicas may have come or gone since the chunk was queued.
+            let missing = self.missing_replicas(chunk_id);
+            if missing != priority {
+                self.enqueue_replication(chunk_id);
+                continue;
+            }
+            // The chunk is being snapshotted, so its replicas are about to change version.
+            if self.bumping_chunks.contains(&chunk_id) {
+                deferred.push(chunk_id);
+                continue;
+            }
+
+            let locations: Vec<&String> = self.chunk_locations.get(&chunk_id).into_iter().flatten()
+                .filter(|x| self.is_alive(x))
+                .collect();
+            let Some(source) = locations.first() else {
+                println!("[master] chunk {chunk_id} has no live replicas; cannot re-replicate");
+                continue;
+            };
+
+            // Copy to the chunkserver with the most space free, spreading copies across chunkservers.
+            let destination = self.chunkservers.values()
+                .filter(|x| x.is_alive() && !locations.contains(&&x.id))
+                .filter(|x| !replications.iter().any(|r: &Replication| r.destination == x.id))
+                .max_by_key(|x| x.disk_free);
+            let Some(destination) = destination else {
+                deferred.push(chunk_id);
+                continue;
+            };
+
+            let (source, destination) = (source.to_string(), destination.id.clone());
+            let VersionBump { version, replicas, .. } = self.begin_version_bumps([chunk_id]).remove(0);
+            replications.push(Replication { chunk_id, version, source, destination, replicas });
+        }
+
+        deferred.into_iter().for_each(|chunk_id| self.enqueue_replication(chunk_id));
+        replications
+    }
+
+    fn finish_replication(&mut self, replication: Replication, res: Result<(), ChunkserverError>) {
+        let Replication { chunk_id, version, source, destination, .. } = replication;
+        match res {
+            // The chunk may have been mutated while it was being copied.
+            Ok(()) if version < self.state.chunk_version(chunk_id) => {
+                println!("[master] chunk {chunk_id} was mutated while being copied to {destination}");
+            }
+            Ok(()) => {
+                println!("[master] re-replicated chunk {chunk_id} from {source} to {destination}");
+                self.add_chunk_location(chunk_id, &destination);
+            }
+            Err(err) => {
+                println!("[master] failed to re-replicate chunk {chunk_id} from {source} to {destination}: {err:?}");
+            }
+        }
+        // Queue the chunk again if it is still missing replicas.
+        self.enqueue_replication(chunk_id);
+    }
+
+    /// Grant a new version of each chunk, returning the live replicas to tell it to.
     ///
-    /// Chunkserver file API's.
-    /// 
+    /// Chunks already being bumped are skipped, as that bump grants the new version. Replicas
+    /// are told without holding the master lock, and then the bumps are finished with
+    /// `finish_version_bumps`.
+    fn begin_version_bumps(&mut self, chunks: impl IntoIterator<Item = u64>) -> Vec<VersionBump> {
+        let mut bumps = vec![];
+        for chunk_id in chunks {
+            if !self.bumping_chunks.insert(chunk_id) {
+                continue;
+            }
+            let replicas = self.chunk_locations.get(&chunk_id).into_iter().flatten()
+                .filter(|x| self.is_alive(x))
+                .cloned()
+                .collect();
+            bumps.push(VersionBump { chunk_id, version: self.state.chunk_version(chunk_id) + 1, replicas });
+        }
+        bumps
+    }
 
-    /// Appends to a file path, creating the file if it does not exist.
-    pub fn append_file(&mut self, op: AppendOperation) -> Result<(), String> {
-        // 1. Allocate chunk ID for each chunk.
-        let hash_to_chunk_id: HashMap<ChunkHash, u64> = op.chunk_sequence.iter().map(|hash| {
-            let chunk_id = self.allocate_chunk();
-            (hash.clone(), chunk_id)
-        }).collect();
+    /// Log the new versions of chunks which any replica was told, along with `records`.
+    ///
+    /// Replicas which weren't told are stale, and are dropped from the chunk's locations. Their
+    /// chunkservers are asked for a full report, so the stale replicas are found and deleted.
+    fn finish_version_bumps(&mut self, bumps: &[VersionBump], updated: &HashSet<(u64, String)>, mut records: Vec<LogRecord>) -> Result<(), MasterError> {
+        for bump in bumps {
+            self.bumping_chunks.remove(&bump.chunk_id);
+        }
+        let bumped: Vec<u64> = bumps.iter()
+            .map(|bump| bump.chunk_id)
+            .filter(|chunk_id| updated.iter().any(|(x, _)| x == chunk_id))
+            .collect();
+        if !bumped.is_empty() {
+            records.push(LogRecord::BumpChunkVersions { chunks: bumped.clone() });
+        }
+        if !records.is_empty() {
+            self.log_op(records)?;
+        }
 
-        // 2. Commit each chunk.
-        let mut committed_chunk_locations: HashMap<u64, Vec<String>> = HashMap::new();
-
-        // For each chunk and its locations:
-        for (chunk_hash, chunk_locations) in op.chunk_locations.iter() {
-            // Get the chunk ID.
-            let chunk_id = hash_to_chunk_id[chunk_hash];
-
-            // For each location.
-            for (i, chunk_location) in chunk_locations.iter().enumerate() {
-                // 1. Get the chunkserver.
-                let chunkserver = self.network.lock().unwrap().get_node(chunk_location).unwrap();
-                // TODO handle chunkserver not found in real implementation.
-                
-                // 2. Commit the chunk.
-                let res = chunkserver.lock().unwrap().commit_chunk(*chunk_hash, chunk_id);
-                
-                // If failed, just ignore.
-                if res.is_err() {
-                    println!("[master] failed to commit chunk {} to {}; skipping", chunk_id, chunk_location);
-                    continue;
+        for chunk_id in bumped {
+            let stale: Vec<String> = self.chunk_locations.get(&chunk_id).into_iter().flatten()
+                .filter(|x| !updated.contains(&(chunk_id, x.to_string())))
+                .cloned()
+                .collect();
+            for chunkserver_id in stale {
+                self.remove_chunk_location(chunk_id, &chunkserver_id);
+                if let Some(chunkserver_info) = self.chunkservers.get_mut(&chunkserver_id) {
+                    chunkserver_info.full_report_needed = true;
                 }
+            }
+            self.enqueue_replication(chunk_id);
+        }
+        Ok(())
+    }
+
 
-                // 3. Store the chunk location.
-                committed_chunk_locations
-                    .entry(chunk_id)
-                    .or_default()
-                    .push(chunk_location.clone());
+    //
+    // Chunkserver file API's.
+    //
+
+    /// Appends to a file path, creating the file if it does not exist.
+    ///
+    /// The master is only locked to allocate the chunks and to record the append. Chunks are
+    /// committed while holding namespace locks on the file, so operations on other files go ahead.
+    pub fn append_file(master: &Mutex<MasterServer>, mut op: AppendOperation) -> Result<(), MasterError> {
+        op.file_path = normalize_path(&op.file_path);
+        if is_reserved(&op.file_path) {
+            return Err(MasterError::InvalidPath);
+        }
+        let _locks = MasterServer::lock_namespace(master, &[], &[&op.file_path]);
+
+        // 1. Allocate chunk ID for each chunk.
+        // This is logged before any chunk is committed, so IDs are never reused after a crash.
+        let (network, first_chunk_id, versions) = {
+            let mut master = master.lock().unwrap();
+            if master.state.namespace.is_dir(&op.file_path) || !master.state.namespace.can_create(&op.file_path) {
+                return Err(MasterError::InvalidPath);
             }
+            let first_chunk_id = master.allocate_chunks(op.chunk_sequence.len() as u64)?;
+            // Until the append is recorded, nothing references the chunks, but they aren't garbage.
+            master.appending_chunks.extend(first_chunk_id..first_chunk_id + op.chunk_sequence.len() as u64);
+            let versions: Vec<u64> = (0..op.chunk_sequence.len() as u64).map(|i| master.state.chunk_version(first_chunk_id + i)).collect();
+            (master.network.clone(), first_chunk_id, versions)
+        };
+
+        // 2. Commit each chunk, in sequence order.
+        let committed_chunk_locations = commit_chunks(&network, &op, first_chunk_id, &versions);
+
+        // 3. Record the append.
+        let mut master = master.lock().unwrap();
+        for chunk_id in first_chunk_id..first_chunk_id + op.chunk_sequence.len() as u64 {
+            master.appending_chunks.remove(&chunk_id);
         }
+        master.finish_append(op, first_chunk_id, committed_chunk_locations?)
+    }
 
-        // 3. Update the file entry.
-        let file = self.state.file_table.entry(op.file_path.clone()).or_default();
-        file.chunks.extend(committed_chunk_locations.keys());
-        file.length += op.length;
+    /// Log and apply the file update. The client is only acknowledged once this is durable.
+    fn finish_append(&mut self, op: AppendOperation, first_chunk_id: u64, committed_chunk_locations: HashMap<u64, Vec<String>>) -> Result<(), MasterError> {
+        let chunk_ids: Vec<u64> = (first_chunk_id..first_chunk_id + op.chunk_sequence.len() as u64).collect();
+        let mut records = vec![];
+        if !self.state.file_table.contains_key(&op.file_path) {
+            records.push(LogRecord::CreateFile { path: op.file_path.clone() });
+        }
+        records.push(LogRecord::AppendChunks { path: op.file_path.clone(), chunks: chunk_ids, length: op.length });
+        self.log_op(records)?;
         println!("[master] append {} bytes={} chunks={}", op.file_path, op.length, committed_chunk_locations.keys().len());
 
-        // 4. Update the chunk locations.
-        // TODO check what Rust does with the iterator here.
-        self.chunk_locations.extend(committed_chunk_locations);
+        // Update the chunk locations.
+        for (chunk_id, locations) in committed_chunk_locations {
+            locations.iter().for_each(|location| self.add_chunk_location(chunk_id, location));
+            // Chunks which failed to commit to some chunkservers are re-replicated.
+            self.enqueue_replication(chunk_id);
+        }
 
         Ok(())
     }
 
-    /// 
-    /// Chunkserver control API's.
-    /// 
-    
+    /// Lock paths in the namespace for an operation, see `NamespaceLocks::lock`.
+    /// The master isn't held locked while waiting, so other operations can go ahead.
+    pub fn lock_namespace(master: &Mutex<MasterServer>, reads: &[&str], writes: &[&str]) -> NamespaceGuard {
+        let namespace_locks = master.lock().unwrap().namespace_locks.clone();
+        namespace_locks.lock(reads, writes)
+    }
+
+    /// Whether a chunk can be deleted. See `MasterServerState::is_garbage`.
+    fn is_garbage(&self, chunk_id: u64) -> bool {
+        !self.appending_chunks.contains(&chunk_id) && self.state.is_garbage(chunk_id)
+    }
+
+    //
+    // Chunkserver control API's.
+    //
+
     /// Receive a heartbeat from a chunkserver.
-    pub fn receive_heartbeat(&mut self, chunkserver_id: String, disk_used: u64, disk_free: u64) {
-        println!("Received heartbeat from chunkserver: {chunkserver_id}");
+    pub fn receive_heartbeat(&mut self, heartbeat: Heartbeat) -> HeartbeatResponse {
+        let Heartbeat { chunkserver_id, disk_used, disk_free, full_report, added_chunks, removed_chunks, corrupt_chunks } = heartbeat;
 
-        // Update the last seen time for the chunkserver.
         if let Some(chunkserver_info) = self.chunkservers.get_mut(&chunkserver_id) {
-            chunkserver_info.last_seen = 0;
+            if !chunkserver_info.alive {
+                println!("[master] chunkserver {chunkserver_id} is alive again");
+            }
+            chunkserver_info.last_seen = Instant::now();
+            chunkserver_info.alive = true;
+            chunkserver_info.disk_used = disk_used;
+            chunkserver_info.disk_free = disk_free;
         } else {
             // Add the chunkserver to the list of chunkserver's.
+            println!("[master] chunkserver {chunkserver_id} registered");
+            self.network.lock().unwrap().register_node(&chunkserver_id);
             self.chunkservers.insert(chunkserver_id.clone(), ChunkserverInfo {
                 id: chunkserver_id.clone(),
-                last_seen: 0,
+                last_seen: Instant::now(),
+                alive: true,
                 ip: String::new(),
                 port: 0,
-                chunks: Vec::new(),
+                chunks: HashSet::new(),
+                garbage: HashMap::new(),
+                full_report_needed: false,
                 disk_used,
                 disk_free,
             });
+
+            // We can't apply changes without knowing what they're relative to.
+            if !full_report {
+                return HeartbeatResponse { full_report_needed: true, delete_chunks: HashMap::new() };
+            }
+        }
+
+        if full_report {
+            // Forget chunks which are no longer held.
+            let chunks: HashSet<u64> = added_chunks.keys().copied().collect();
+            let previous = std::mem::take(&mut self.chunkservers.get_mut(&chunkserver_id).unwrap().chunks);
+            for chunk_id in previous.difference(&chunks) {
+                self.remove_chunk_location(*chunk_id, &chunkserver_id);
+                self.enqueue_replication(*chunk_id);
+            }
+            println!("[master] chunkserver {chunkserver_id} reported {} chunks", chunks.len());
+        }
+        for (chunk_id, version) in added_chunks {
+            let current = self.state.chunk_version(chunk_id);
+            if version < current {
+                println!("[master] chunkserver {chunkserver_id} has stale replica of chunk {chunk_id} (version {version} < {current})");
+                self.remove_chunk_location(chunk_id, &chunkserver_id);
+                self.chunkservers.get_mut(&chunkserver_id).unwrap().garbage.insert(chunk_id, version);
+                self.enqueue_replication(chunk_id);
+                continue;
+            }
+            // The master granted a new version, and told the replica, but failed to log it.
+            // The other replicas may not have been told, so they are checked again.
+            //
+            // A version is only granted one past the last logged, so a replica further ahead, or
+            // of a chunk the master never allocated, wasn't granted by this master. It is left
+            // alone, but not read from.
+            if version > current && !self.bumping_chunks.contains(&chunk_id) {
+                if version > current + 1 || chunk_id >= self.state.chunk_counter {
+                    println!("[master] chunkserver {chunkserver_id} has chunk {chunk_id} at version {version}, which was never granted");
+                    continue;
+                }
+                println!("[master] chunkserver {chunkserver_id} has chunk {chunk_id} at version {version}, ahead of {current}");
+                if let Err(err) = self.log(vec![LogRecord::SetChunkVersion { chunk_id, version }]) {
+                    println!("[master] {err}");
+                    continue;
+                }
+                for other in self.chunk_locations.remove(&chunk_id).unwrap_or_default() {
+                    if let Some(chunkserver_info) = self.chunkservers.get_mut(&other) {
+                        chunkserver_info.chunks.remove(&chunk_id);
+                        chunkserver_info.full_report_needed = true;
+                    }
+                }
+            }
+
+            self.add_chunk_location(chunk_id, &chunkserver_id);
+            // After a restart, this is how the master learns which chunks are under-replicated.
+            if full_report {
+                self.enqueue_replication(chunk_id);
+            }
+        }
+        for chunk_id in removed_chunks {
+            self.remove_chunk_location(chunk_id, &chunkserver_id);
+            self.enqueue_replication(chunk_id);
+        }
+        for chunk_id in corrupt_chunks {
+            println!("[master] chunkserver {chunkserver_id} has corrupt replica of chunk {chunk_id}");
+            self.remove_chunk_location(chunk_id, &chunkserver_id);
+            self.enqueue_replication(chunk_id);
         }
+
+        let chunkserver_info = self.chunkservers.get_mut(&chunkserver_id).unwrap();
+        let full_report_needed = std::mem::take(&mut chunkserver_info.full_report_needed) && !full_report;
+        let delete_chunks = std::mem::take(&mut chunkserver_info.garbage);
+        HeartbeatResponse { full_report_needed, delete_chunks }
     }
 
+    fn add_chunk_location(&mut self, chunk_id: u64, chunkserver_id: &str) {
+        if let Some(chunkserver_info) = self.chunkservers.get_mut(chunkserver_id) {
+            chunkserver_info.chunks.insert(chunk_id);
+        }
+        let locations = self.chunk_locations.entry(chunk_id).or_default();
+        if !locations.iter().any(|x| x == chunkserver_id) {
+            locations.push(chunkserver_id.to_string());
+        }
+    }
 
+    fn remove_chunk_location(&mut self, chunk_id: u64, chunkserver_id: &str) {
+        if let Some(chunkserver_info) = self.chunkservers.get_mut(chunkserver_id) {
+            chunkserver_info.chunks.remove(&chunk_id);
+        }
+        if let Some(locations) = self.chunk_locations.get_mut(&chunk_id) {
+            locations.retain(|x| x != chunkserver_id);
+            if locations.is_empty() {
+                self.chunk_locations.remove(&chunk_id);
+            }
+        }
+    }
+
+    /// Find chunks which chunkservers have reported holding, but which no file references,
+    /// and have them deleted in reply to the chunkservers' next heartbeats.
     ///
-    /// Client API's.
-    /// 
+    /// These are chunks of deleted files, and chunks committed by appends which then failed.
+    /// Deleted files are removed here, once their grace period has passed.
+    pub fn collect_garbage(&mut self) {
+        self.last_garbage_collection = Instant::now();
 
-    /// List the files in a directory.
-    pub fn ls(&self, path: &str) -> Vec<String> {
-        let mut result = Vec::new();
-        let base_path = Path::new(path);
-        
-        for (file_path, _) in self.state.file_table.iter() {
-            let file_path = Path::new(file_path);
-            
-            if file_path.starts_with(base_path) {
-                // Check that file is directly inside the directory, not in subdirectories
-                if let Some(relative_path) = file_path.strip_prefix(base_path).ok() {
-                    if relative_path.parent().is_none() {
-                        result.push(file_path.to_string_lossy().into_owned());
-                    }
+        let reclaim_before = unix_millis().saturating_sub(self.deletion_grace_period.as_millis() as u64);
+        let expired: Vec<LogRecord> = self.state.file_table.keys()
+            .filter(|path| parse_deleted_name(path).is_some_and(|(_, deleted_at)| deleted_at < reclaim_before))
+            .map(|path| LogRecord::RemoveFile { path: path.clone() })
+            .collect();
+        if !expired.is_empty() {
+            println!("[master] reclaiming {} deleted files", expired.len());
+            if let Err(err) = self.log(expired) {
+                println!("[master] {err}");
+            }
+        }
+
+        let mut garbage = vec![];
+        for chunkserver_info in self.chunkservers.values() {
+            for chunk_id in chunkserver_info.chunks.iter() {
+                if self.is_garbage(*chunk_id) {
+                    garbage.push((chunkserver_info.id.clone(), *chunk_id));
                 }
             }
         }
-        
-        result
+
+        for (chunkserver_id, chunk_id) in garbage.iter() {
+            self.remove_chunk_location(*chunk_id, chunkserver_id);
+            // Any version of the chunk can be deleted.
+            self.chunkservers.get_mut(chunkserver_id).unwrap().garbage.insert(*chunk_id, u64::MAX);
+        }
+        if !garbage.is_empty() {
+            println!("[master] garbage collection found {} unreferenced chunk replicas", garbage.len());
+        }
     }
 
-    /// List the file tree for a path prefix (akin to `tree`).
-    pub fn ls_tree(&self, path: &str) -> Vec<String> {
-        let mut result = Vec::new();
-        for (file_path, _) in self.state.file_table.iter() {
-            if file_path.starts_with(path) {
-                result.push(file_path.clone());
+    /// Mark chunkservers which have missed too many heartbeats as dead.
+    /// Their chunks are queued for re-replication.
+    pub fn check_liveness(&mut self) {
+        let mut lost_chunks = vec![];
+        for chunkserver_info in self.chunkservers.values_mut() {
+            if chunkserver_info.alive && !chunkserver_info.is_alive() {
+                println!("[master] chunkserver {} missed {} heartbeats; marking dead", chunkserver_info.id, MAX_MISSED_HEARTBEATS);
+                chunkserver_info.alive = false;
+                lost_chunks.extend(chunkserver_info.chunks.iter().copied());
+            }
+        }
+        lost_chunks.into_iter().for_each(|chunk_id| self.enqueue_replication(chunk_id));
+    }
+
+
+    //
+    // Client API's.
+    //
+
+    /// Delete a file by renaming it to a hidden name. Its chunks are reclaimed once the
+    /// deletion grace period has passed, and until then it can be restored with `undelete`.
+    ///
+    /// Deleting a file which is already hidden removes it straight away.
+    pub fn delete(&mut self, path: &str) -> Result<(), MasterError> {
+        let path = &normalize_path(path);
+        if !self.state.file_table.contains_key(path) {
+            return Err(MasterError::FileNotFound);
+        }
+
+        let record = if parse_deleted_name(path).is_some() {
+            LogRecord::RemoveFile { path: path.to_string() }
+        } else {
+            LogRecord::RenameFile { from: path.to_string(), to: self.hidden_name(path) }
+        };
+        self.log_op(vec![record])?;
+        println!("[master] delete {path}");
+        Ok(())
+    }
+
+    /// A hidden name to delete the file at `path` to, which isn't already taken.
+    fn hidden_name(&self, path: &str) -> String {
+        let mut deleted_at = unix_millis();
+        while self.state.file_table.contains_key(&deleted_name(path, deleted_at)) {
+            deleted_at += 1;
+        }
+        deleted_name(path, deleted_at)
+    }
+
+    /// Whether a directory holds nothing but deleted files.
+    fn is_empty_dir(&self, path: &str) -> bool {
+        let entries = self.state.namespace.list(path).unwrap_or_default();
+        entries.iter().all(|(name, is_dir)| !is_dir && parse_deleted_name(&format!("{path}/{name}")).is_some())
+    }
+
+    /// Restore the most recently deleted file at `path`, if it hasn't been reclaimed.
+    pub fn undelete(&mut self, path: &str) -> Result<(), MasterError> {
+        let path = &normalize_path(path);
+        if self.state.namespace.get(path).is_some() {
+            return Err(MasterError::FileExists);
+        }
+
+        let (dir, _) = path.rsplit_once('/').unwrap();
+        let hidden = self.state.namespace.list(dir).unwrap_or_default().into_iter()
+            .map(|(name, _)| format!("{dir}/{name}"))
+            .filter_map(|hidden| parse_deleted_name(&hidden).map(|(original, deleted_at)| (hidden, original, deleted_at)))
+            .filter(|(_, original, _)| original == path)
+            .max_by_key(|(_, _, deleted_at)| *deleted_at)
+            .map(|(hidden, _, _)| hidden);
+        let Some(hidden) = hidden else { return Err(MasterError::FileNotFound) };
+
+        self.log_op(vec![LogRecord::RenameFile { from: hidden, to: path.to_string() }])?;
+        println!("[master] undelete {path}");
+        Ok(())
+    }
+
+    /// Atomically rename a file, or a directory and everything in it.
+    ///
+    /// If `overwrite` is set, a file at `to` is replaced, and deleted as by `delete`, or an
+    /// empty directory at `to` is replaced. Otherwise the rename fails with `FileExists` if
+    /// `to` exists. A file can't replace a directory, nor a directory a file.
+    pub fn rename(&mut self, from: &str, to: &str, overwrite: bool) -> Result<(), MasterError> {
+        let from = &normalize_path(from);
+        let to = &normalize_path(to);
+        if from == "/" || to == "/" || to.starts_with(&format!("{from}/")) || from.starts_with(&format!("{to}/"))
+            || is_reserved(to) || !self.state.namespace.can_create(to) {
+            return Err(MasterError::InvalidPath);
+        }
+        let Some(entry) = self.state.namespace.get(from) else {
+            return Err(MasterError::FileNotFound);
+        };
+        if from == to {
+            return Ok(());
+        }
+
+        let mut replaced = None;
+        match (self.state.namespace.get(to), entry) {
+            (None, _) => {}
+            (Some(_), _) if !overwrite => return Err(MasterError::FileExists),
+            (Some(Entry::File), Entry::File) => replaced = Some(self.hidden_name(to)),
+            (Some(Entry::Directory(_)), Entry::Directory(_)) => {
+                // Deleted files left in the directory are reclaimed, as by `rmdir`.
+                if !self.is_empty_dir(to) {
+                    return Err(MasterError::DirectoryNotEmpty);
+                }
             }
+            _ => return Err(MasterError::InvalidPath),
         }
+        // One record, so a crash can't leave `to` deleted but `from` not yet renamed.
+        self.log_op(vec![LogRecord::Rename { from: from.to_string(), to: to.to_string(), replaced }])?;
+        println!("[master] rename {from} {to}");
+        Ok(())
+    }
+
+    /// Snapshot a file or directory to `to`, by copying its metadata.
+    ///
+    /// The copy references the same chunks as the original. Chunks are never modified once
+    /// committed, as appends always add new chunks, so they can be shared without being copied.
+    /// A chunk is reclaimed once no file references it.
+    ///
+    /// Snapshotting is granted as a mutation of the chunks, so their replicas are told new
+    /// versions, without holding the master lock, before the snapshot is logged.
+    pub fn snapshot(master: &Mutex<MasterServer>, from: &str, to: &str) -> Result<(), MasterError> {
+        let from = &normalize_path(from);
+        let to = &normalize_path(to);
+        let (network, bumps) = {
+            let mut master = master.lock().unwrap();
+            let namespace = &master.state.namespace;
+            if to.starts_with(&format!("{}/", from.trim_end_matches('/'))) || is_reserved(to) || !namespace.can_create(to) {
+                return Err(MasterError::InvalidPath);
+            }
+            if namespace.get(from).is_none() {
+                return Err(MasterError::FileNotFound);
+            }
+            if namespace.get(to).is_some() {
+                return Err(MasterError::FileExists);
+            }
+            let chunks: Vec<u64> = namespace.files(from).iter()
+                .flat_map(|path| master.state.file_table[path].chunks.clone())
+                .collect();
+            (master.network.clone(), master.begin_version_bumps(chunks))
+        };
+
+        let updated = update_versions(&network, &bumps);
+        let record = LogRecord::Snapshot { from: from.to_string(), to: to.to_string() };
+        master.lock().unwrap().finish_version_bumps(&bumps, &updated, vec![record])?;
+        println!("[master] snapshot {from} {to}");
+        Ok(())
+    }
+
+    /// Create a directory, and any missing parent directories.
+    pub fn mkdir(&mut self, path: &str) -> Result<(), MasterError> {
+        let path = &normalize_path(path);
+        if self.state.namespace.get(path).is_some() {
+            return Err(MasterError::FileExists);
+        }
+        if is_reserved(path) || !self.state.namespace.can_create(path) {
+            return Err(MasterError::InvalidPath);
+        }
+
+        self.log_op(vec![LogRecord::Mkdir { path: path.to_string() }])?;
+        println!("[master] mkdir {path}");
+        Ok(())
+    }
+
+    /// Remove an empty directory. Deleted files still in it are reclaimed straight away.
+    pub fn rmdir(&mut self, path: &str) -> Result<(), MasterError> {
+        let path = &normalize_path(path);
+        if path == "/" {
+            return Err(MasterError::InvalidPath);
+        }
+        match self.state.namespace.get(path) {
+            Some(Entry::Directory(_)) => {}
+            Some(Entry::File) => return Err(MasterError::InvalidPath),
+            None => return Err(MasterError::FileNotFound),
+        }
+        if !self.is_empty_dir(path) {
+            return Err(MasterError::DirectoryNotEmpty);
+        }
+
+        self.log_op(vec![LogRecord::Rmdir { path: path.to_string() }])?;
+        println!("[master] rmdir {path}");
+        Ok(())
+    }
+
+    /// List a directory. Subdirectories end with a `/`.
+    pub fn ls(&self, path: &str) -> Vec<String> {
+        let path = normalize_path(path);
+        let dir = path.trim_end_matches('/');
+        let Some(entries) = self.state.namespace.list(&path) else { return vec![] };
+        entries.into_iter()
+            .map(|(name, is_dir)| if is_dir { format!("{dir}/{name}/") } else { format!("{dir}/{name}") })
+            // Deleted files are hidden.
+            .filter(|path| parse_deleted_name(path).is_none())
+            .collect()
+    }
+
+    /// List the file tree under a path (akin to `tree`). Directories end with a `/`.
+    pub fn ls_tree(&self, path: &str) -> Vec<String> {
+        let mut result = Vec::new();
+        self.state.namespace.walk(path, &mut |path, entry| match entry {
+            Entry::Directory(_) => result.push(format!("{}/", path.trim_end_matches('/'))),
+            Entry::File => if parse_deleted_name(path).is_none() {
+                result.push(path.to_string());
+            },
+        });
         result
     }
 
@@ -289,38 +1269,185 @@ impl MasterServer {
     }
 
     /// Get the metadata for a file.
-    pub fn stat(&self, path: &str) -> StatInfo {
-        let file = self.state.file_table.get(path).unwrap();
-        StatInfo { length: file.length }
+    pub fn stat(&self, path: &str) -> Result<StatInfo, MasterError> {
+        let Some(file) = self.state.file_table.get(&normalize_path(path)) else { return Err(MasterError::FileNotFound) };
+        Ok(StatInfo { length: file.length })
     }
 
     /// Get chunks and their locations for a read operation.
     pub fn get_read_infos(&self, path: &str, offset: u64, length: u64) -> Result<ReadOperationInfo, MasterError> {
         println!("[master] get_read_infos path={} offset={} length={}", path, offset, length);
 
-        let Some(file) = self.state.file_table.get(path) else { return Err(MasterError::FileNotFound) };
-        let offset_chunk = offset / CHUNK_SIZE_BYTES as u64;
-        let end_chunk = (offset + length) / CHUNK_SIZE_BYTES as u64;
+        let Some(file) = self.state.file_table.get(&normalize_path(path)) else { return Err(MasterError::FileNotFound) };
 
-        // The offset chunk is the first chunk we need to read.
         // If the offset exceeds the file length, return an EOF error.
+        // Reads past the end of the file are truncated.
         if file.length < offset {
             return Err(MasterError::EndOfFile);
         }
-
-        // The end chunk is the last chunk we need to read.
-        // If the end chunk exceeds the file length, truncate it.
-        let end_chunk = std::cmp::min(end_chunk, (file.length as f64 / CHUNK_SIZE_BYTES as f64).ceil() as u64);
+        let end = std::cmp::min(offset.saturating_add(length), file.length);
 
         let mut chunk_reads = vec![];
-        // For each chunk, get the chunk ID and locations.
-        for i in offset_chunk..=end_chunk {
-            let chunk_id = file.chunks[i as usize];
-            let locations = self.chunk_locations.get(&chunk_id).unwrap();
-            chunk_reads.push(ChunkRead { chunk_id, locations: locations.clone() });
+        // The first chunk to read is the last which starts at or before the offset.
+        let first_chunk = file.chunk_offsets.partition_point(|x| *x <= offset).saturating_sub(1);
+        for i in first_chunk..file.chunks.len() {
+            let chunk_start = file.chunk_offsets[i];
+            let chunk_end = file.chunk_offsets.get(i + 1).copied().unwrap_or(file.length);
+            if chunk_start >= end {
+                break;
+            }
+            let read_start = std::cmp::max(offset, chunk_start);
+            let read_end = std::cmp::min(end, chunk_end);
+            if read_start >= read_end {
+                continue;
+            }
+
+            // For each chunk, get the chunk ID and locations.
+            let chunk_id = file.chunks[i];
+            let locations: Vec<String> = self.chunk_locations.get(&chunk_id).into_iter().flatten()
+                .filter(|x| self.is_alive(x))
+                .cloned()
+                .collect();
+            if locations.is_empty() {
+                return Err(MasterError::ChunkNotFound);
+            }
+            chunk_reads.push(ChunkRead { chunk_id, locations, offset: read_start - chunk_start, length: read_end - read_start });
         }
 
-        Ok(ReadOperationInfo { path: path.to_string(), offset, length, chunk_reads })
+        Ok(ReadOperationInfo { path: path.to_string(), offset, length: end - offset, chunk_reads })
     }
 
 }
+
+/// Tell replicas the new versions of their chunks, returning which replicas were told.
+///
+/// Each chunkserver is told its chunks' versions in turn, and chunkservers are told at once.
+fn update_versions(network: &Arc<Mutex<dyn Transport>>, bumps: &[VersionBump]) -> HashSet<(u64, String)> {
+    let mut updates: HashMap<&String, Vec<&VersionBump>> = HashMap::new();
+    for bump in bumps {
+        for replica in bump.replicas.iter() {
+            updates.entry(replica).or_default().push(bump);
+        }
+    }
+
+    let updated = Mutex::new(HashSet::new());
+    std::thread::scope(|scope| {
+        for (replica, bumps) in updates {
+            let Some(chunkserver) = network.lock().unwrap().get_node(replica) else {
+                println!("[master] chunkserver {} not found", replica);
+                continue;
+            };
+            let updated = &updated;
+            scope.spawn(move || {
+                for bump in bumps {
+                    match chunkserver.update_chunk_version(bump.chunk_id, bump.version) {
+                        Ok(()) => {}
+                        // Each call to a hung chunkserver waits out a timeout, so give up on it.
+                        Err(ChunkserverError::Unavailable) => {
+                            println!("[master] chunkserver {} unavailable; skipping its remaining chunks", replica);
+                            break;
+                        }
+                        Err(err) => {
+                            println!("[master] failed to update version of chunk {} on {}: {err:?}", bump.chunk_id, replica);
+                            continue;
+                        }
+                    }
+                    updated.lock().unwrap().insert((bump.chunk_id, replica.clone()));
+                }
+            });
+        }
+    });
+    updated.into_inner().unwrap()
+}
+
+/// Commit the chunks of an append to the chunkservers they were pushed to, returning
+/// where each was committed.
+///
+/// Each chunkserver commits its chunks in sequence order, and chunkservers commit at once.
+fn commit_chunks(network: &Arc<Mutex<dyn Transport>>, op: &AppendOperation, first_chunk_id: u64, versions: &[u64]) -> Result<HashMap<u64, Vec<String>>, MasterError> {
+    // 1. Group the chunks by the chunkservers they were pushed to.
+    let mut commits: HashMap<&String, Vec<(usize, &ChunkHash)>> = HashMap::new();
+    for (i, chunk_hash) in op.chunk_sequence.iter().enumerate() {
+        for chunk_location in op.chunk_locations.get(chunk_hash).into_iter().flatten() {
+            commits.entry(chunk_location).or_default().push((i, chunk_hash));
+        }
+    }
+
+    // 2. Find the chunkservers, before committing anything.
+    let mut chunkservers = vec![];
+    for (chunk_location, chunks) in commits.iter() {
+        let Some(chunkserver) = network.lock().unwrap().get_node(chunk_location) else {
+            println!("[master] chunkserver {} not found", chunk_location);
+            return Err(MasterError::ChunkserverNotFound);
+        };
+        chunkservers.push((chunk_location, chunkserver, chunks));
+    }
+
+    // 3. Commit the chunks on each chunkserver.
+    let committed = Mutex::new(vec![]);
+    std::thread::scope(|scope| {
+        for (chunk_location, chunkserver, chunks) in chunkservers {
+            let committed = &committed;
+            scope.spawn(move || {
+                for (i, chunk_hash) in chunks {
+                    let chunk_id = first_chunk_id + *i as u64;
+                    // If failed, just ignore.
+                    match chunkserver.commit_chunk(**chunk_hash, chunk_id, versions[*i]) {
+                        Ok(()) => {}
+                        // Each call to a hung chunkserver waits out a timeout, so give up on it.
+                        Err(ChunkserverError::Unavailable) => {
+                            println!("[master] chunkserver {} unavailable; skipping its remaining chunks", chunk_location);
+                            break;
+                        }
+                        Err(_) => {
+                            println!("[master] failed to commit chunk {} to {}; skipping", chunk_id, chunk_location);
+                            continue;
+                        }
+                    }
+                    committed.lock().unwrap().push((chunk_id, (*chunk_location).clone()));
+                }
+            });
+        }
+    });
+
+    // 4. Store the chunk locations.
+    let mut committed_chunk_locations: HashMap<u64, Vec<String>> = HashMap::new();
+    for (chunk_id, chunk_location) in committed.into_inner().unwrap() {
+        committed_chunk_locations.entry(chunk_id).or_default().push(chunk_location);
+    }
+
+    // A chunk with no replicas would leave a hole in the file.
+    for i in 0..op.chunk_sequence.len() as u64 {
+        if !committed_chunk_locations.contains_key(&(first_chunk_id + i)) {
+            println!("[master] chunk {} could not be committed to any chunkserver", first_chunk_id + i);
+            return Err(MasterError::AppendFailed);
+        }
+    }
+
+    Ok(committed_chunk_locations)
+}
+
+/// Fold the sealed operation log segments in `dir` into a new checkpoint, then remove them.
+///
+/// The checkpoint is built from the previous checkpoint and the log, not from the live
+/// master, so it can run in the background without locking the master.
+pub fn write_checkpoint(dir: &Path, sealed_segment: u64) -> std::io::Result<()> {
+    let mut state = MasterServerState::load(dir)?;
+    let entries = OperationLog::read_sealed(dir, sealed_segment)?;
+    entries.iter().for_each(|entry| state.apply(entry));
+    state.write_checkpoint(dir)?;
+    OperationLog::truncate(dir, sealed_segment)?;
+    println!("[master] checkpoint at seq={} ({} log entries folded in)", state.log_seq, entries.len());
+    Ok(())
+}
+
+/// Checkpoint the master every `interval` on a background thread.
+pub fn spawn_checkpointer(master: Arc<Mutex<MasterServer>>, interval: Duration) -> JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        let Some((dir, sealed_segment)) = master.lock().unwrap().begin_checkpoint() else { continue };
+        if let Err(err) = write_checkpoint(&dir, sealed_segment) {
+            println!("[master] checkpoint failed: {err}");
+        }
+    })
+}
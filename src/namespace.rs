@@ -0,0 +1,205 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Condvar, Mutex};
+use serde::{Serialize, Deserialize};
+
+/// An entry in the namespace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Entry {
+    File,
+    Directory(BTreeMap<String, Entry>),
+}
+
+/// The master's directory tree, a prefix tree keyed by path component.
+///
+/// File metadata lives in the file table. This indexes the names, so a directory can be
+/// listed or walked in time proportional to its size, and directories can exist while empty.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Namespace {
+    root: Entry,
+}
+
+impl Default for Namespace {
+    fn default() -> Self {
+        Namespace { root: Entry::Directory(BTreeMap::new()) }
+    }
+}
+
+/// Normalise a path to the form used as a key, e.g. `a//b/` becomes `/a/b`.
+pub fn normalize_path(path: &str) -> String {
+    format!("/{}", components(path).join("/"))
+}
+
+fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|x| !x.is_empty()).collect()
+}
+
+fn join(dir: &str, name: &str) -> String {
+    format!("{}/{name}", dir.trim_end_matches('/'))
+}
+
+impl Namespace {
+    pub fn new() -> Namespace {
+        Namespace::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        matches!(&self.root, Entry::Directory(entries) if entries.is_empty())
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Entry> {
+        let mut entry = &self.root;
+        for name in components(path) {
+            let Entry::Directory(entries) = entry else { return None };
+            entry = entries.get(name)?;
+        }
+        Some(entry)
+    }
+
+    pub fn is_dir(&self, path: &str) -> bool {
+        matches!(self.get(path), Some(Entry::Directory(_)))
+    }
+
+    /// Whether a file or directory could be created at `path`: no ancestor of it is a file.
+    pub fn can_create(&self, path: &str) -> bool {
+        let names = components(path);
+        let mut entry = &self.root;
+        for name in &names[..names.len().saturating_sub(1)] {
+            match entry {
+                Entry::File => return false,
+                Entry::Directory(entries) => match entries.get(*name) {
+                    Some(child) => entry = child,
+                    None => return true,
+                },
+            }
+        }
+        matches!(entry, Entry::Directory(_))
+    }
+
+    /// Insert an entry at `path`, creating its parent directories, and replacing anything there.
+    pub fn insert(&mut self, path: &str, new_entry: Entry) {
+        let names = components(path);
+        let Some((last, parents)) = names.split_last() else { return };
+        let mut entry = &mut self.root;
+        for name in parents {
+            let Entry::Directory(entries) = entry else { panic!("{path}: ancestor is a file") };
+            entry = entries.entry(name.to_string()).or_insert_with(|| Entry::Directory(BTreeMap::new()));
+        }
+        let Entry::Directory(entries) = entry else { panic!("{path}: ancestor is a file") };
+        entries.insert(last.to_string(), new_entry);
+    }
+
+    pub fn remove(&mut self, path: &str) -> Option<Entry> {
+        let names = components(path);
+        let (last, parents) = names.split_last()?;
+        let mut entry = &mut self.root;
+        for name in parents {
+            let Entry::Directory(entries) = entry else { return None };
+            entry = entries.get_mut(*name)?;
+        }
+        let Entry::Directory(entries) = entry else { return None };
+        entries.remove(*last)
+    }
+
+    /// The names in the directory at `path`, and whether each is a directory.
+    pub fn list(&self, path: &str) -> Option<Vec<(String, bool)>> {
+        let Some(Entry::Directory(entries)) = self.get(path) else { return None };
+        Some(entries.iter().map(|(name, entry)| (name.clone(), matches!(entry, Entry::Directory(_)))).collect())
+    }
+
+    /// The paths of the file at `path`, or of every file in the directory at `path`.
+    pub fn files(&self, path: &str) -> Vec<String> {
+        let mut files = vec![];
+        self.walk(path, &mut |path, entry| if let Entry::File = entry {
+            files.push(path.to_string());
+        });
+        files
+    }
+
+    /// Visit the entry at `path` and everything below it, parents before children.
+    pub fn walk(&self, path: &str, visit: &mut impl FnMut(&str, &Entry)) {
+        fn walk_entry(path: &str, entry: &Entry, visit: &mut impl FnMut(&str, &Entry)) {
+            visit(path, entry);
+            if let Entry::Directory(entries) = entry {
+                for (name, child) in entries {
+                    walk_entry(&join(path, name), child, visit);
+                }
+            }
+        }
+        if let Some(entry) = self.get(path) {
+            walk_entry(&normalize_path(path), entry, visit);
+        }
+    }
+}
+
+//
+// Namespace locks.
+//
+
+/// Read/write locks on paths in the namespace, taken for the length of an operation.
+///
+/// As in GFS, an operation read-locks every ancestor of the paths it uses, and read- or
+/// write-locks the paths themselves. A file can't be created while its directory is being
+/// renamed or removed, but files in one directory can be created and appended to at once.
+#[derive(Default)]
+pub struct NamespaceLocks {
+    /// The locked paths, and the number of readers, or `None` if write-locked.
+    held: Mutex<HashMap<String, Option<usize>>>,
+    released: Condvar,
+}
+
+/// Locks held on the namespace, released when dropped.
+pub struct NamespaceGuard {
+    locks: Arc<NamespaceLocks>,
+    /// Each path locked, and whether it is write-locked.
+    paths: HashMap<String, bool>,
+}
+
+impl NamespaceLocks {
+    pub fn new() -> NamespaceLocks {
+        NamespaceLocks::default()
+    }
+
+    /// Lock `reads` for reading and `writes` for writing, along with their ancestors for reading.
+    ///
+    /// The locks are taken all at once, waiting until none conflict, so operations can't deadlock.
+    pub fn lock(self: &Arc<Self>, reads: &[&str], writes: &[&str]) -> NamespaceGuard {
+        let mut paths: HashMap<String, bool> = HashMap::new();
+        for (path, write) in reads.iter().map(|x| (x, false)).chain(writes.iter().map(|x| (x, true))) {
+            let names = components(path);
+            for i in 1..names.len() {
+                paths.entry(format!("/{}", names[..i].join("/"))).or_insert(false);
+            }
+            *paths.entry(normalize_path(path)).or_insert(write) |= write;
+        }
+
+        let mut held = self.held.lock().unwrap();
+        while !paths.iter().all(|(path, write)| match held.get(path) {
+            None => true,
+            Some(readers) => !write && readers.is_some(),
+        }) {
+            held = self.released.wait(held).unwrap();
+        }
+        for (path, write) in paths.iter() {
+            if *write {
+                held.insert(path.clone(), None);
+            } else {
+                let readers = held.entry(path.clone()).or_insert(Some(0));
+                *readers = Some(readers.unwrap() + 1);
+            }
+        }
+        NamespaceGuard { locks: self.clone(), paths }
+    }
+}
+
+impl Drop for NamespaceGuard {
+    fn drop(&mut self) {
+        let mut held = self.locks.held.lock().unwrap();
+        for (path, write) in self.paths.iter() {
+            match held.get_mut(path) {
+                Some(Some(readers)) if !write && *readers > 1 => *readers -= 1,
+                _ => { held.remove(path); }
+            }
+        }
+        self.locks.released.notify_all();
+    }
+}
@@ -0,0 +1,190 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use crate::common::sync_dir;
+
+/// A mutation of the master's namespace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LogRecord {
+    /// A file was created.
+    CreateFile { path: String },
+    /// Chunk IDs below `next_chunk_id` were handed out, and must never be reused.
+    AllocateChunks { next_chunk_id: u64 },
+    /// Committed chunks were appended to a file.
+    AppendChunks { path: String, chunks: Vec<u64>, length: u64 },
+    /// A file was renamed.
+    RenameFile { from: String, to: String },
+    /// A file or directory was renamed, replacing anything at `to`. A file replaced is
+    /// moved to `replaced`, a hidden name, so it can be undeleted.
+    Rename { from: String, to: String, replaced: Option<String> },
+    /// A file was removed, dropping its references to its chunks.
+    RemoveFile { path: String },
+    /// A file or directory was copied, sharing chunks with the original.
+    Snapshot { from: String, to: String },
+    /// A directory was created, along with any missing parents.
+    Mkdir { path: String },
+    /// A directory was removed, along with any hidden deleted files in it.
+    Rmdir { path: String },
+    /// Mutations were granted on chunks, so their versions were incremented.
+    BumpChunkVersions { chunks: Vec<u64> },
+    /// A replica was found at a version the master granted but failed to log.
+    SetChunkVersion { chunk_id: u64, version: u64 },
+}
+
+/// A record and its position in the log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub seq: u64,
+    pub record: LogRecord,
+}
+
+/// The master's operation log.
+///
+/// The log is split into segment files (`oplog.{n}`) in a directory. Each segment is
+/// append-only, with one JSON-encoded entry per line. Entries are fsynced before
+/// `append` returns, so a mutation that has been acknowledged survives a crash. The directory
+/// is fsynced whenever a segment is created or removed, so segments can't go missing either.
+///
+/// Once a segment is sealed with `roll`, it can be folded into a checkpoint and removed.
+pub struct OperationLog {
+    dir: PathBuf,
+    /// The number of the segment being appended to.
+    segment: u64,
+    file: File,
+    /// The length of the current segment.
+    len: u64,
+    /// Whether anything has been appended to the current segment.
+    dirty: bool,
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("oplog.{segment:08}"))
+}
+
+/// List the log segments in `dir`, in order.
+fn segments(dir: &Path) -> std::io::Result<Vec<u64>> {
+    let mut segments = vec![];
+    for file in std::fs::read_dir(dir)? {
+        let name = file?.file_name();
+        if let Some(segment) = name.to_str().and_then(|x| x.strip_prefix("oplog.")?.parse().ok()) {
+            segments.push(segment);
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// Read the entries of one segment, returning them along with the length of the valid prefix.
+///
+/// Reading stops at a torn entry (from a crash mid-write), which may end partway through
+/// a multi-byte character.
+fn read_segment(path: &Path) -> std::io::Result<(Vec<LogEntry>, u64)> {
+    let file = File::open(path)?;
+    let mut entries = vec![];
+    let mut valid_len = 0;
+    let mut reader = BufReader::new(file);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line)?;
+        if n == 0 {
+            break;
+        }
+        match serde_json::from_slice::<LogEntry>(&line) {
+            Ok(entry) if line.ends_with(b"\n") => {
+                entries.push(entry);
+                valid_len += n as u64;
+            }
+            _ => {
+                println!("[oplog] discarding torn entry at offset {valid_len} in {}", path.display());
+                break;
+            }
+        }
+    }
+    Ok((entries, valid_len))
+}
+
+impl OperationLog {
+    /// Open the log in `dir`, creating it if needed, and return the entries it contains.
+    pub fn open(dir: PathBuf) -> std::io::Result<(OperationLog, Vec<LogEntry>)> {
+        let mut entries = vec![];
+        let segments = segments(&dir)?;
+        for segment in segments.iter() {
+            entries.extend(read_segment(&segment_path(&dir, *segment))?.0);
+        }
+
+        // Append to the last segment, dropping anything after its last complete entry.
+        let segment = segments.last().copied().unwrap_or(0);
+        let path = segment_path(&dir, segment);
+        let mut file = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(&path)?;
+        sync_dir(&dir)?;
+        let (tail, valid_len) = read_segment(&path)?;
+        file.set_len(valid_len)?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok((OperationLog { dir, segment, file, len: valid_len, dirty: !tail.is_empty() }, entries))
+    }
+
+    /// Append entries to the log and flush them to disk.
+    ///
+    /// If the entries can't be written, the log is left as it was and an error is returned.
+    /// Panics if they can't be flushed, as it's then unknown whether they reached the disk.
+    pub fn append(&mut self, entries: &[LogEntry]) -> std::io::Result<()> {
+        let mut buf = vec![];
+        for entry in entries {
+            serde_json::to_writer(&mut buf, entry)?;
+            buf.push(b'\n');
+        }
+        if let Err(err) = self.file.write_all(&buf) {
+            // Cut off anything partly written, or it would read as a torn entry, and every
+            // entry appended after it would be discarded when the log is next opened.
+            self.file.set_len(self.len)
+                .and_then(|_| self.file.seek(SeekFrom::Start(self.len)))
+                .expect("failed to roll back a partial write to the operation log");
+            return Err(err);
+        }
+        self.file.sync_data().expect("failed to flush the operation log");
+        self.len += buf.len() as u64;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// The directory holding the log segments.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Seal the current segment and start appending to a new one.
+    ///
+    /// Returns the number of the sealed segment, or `None` if it was empty.
+    pub fn roll(&mut self) -> std::io::Result<Option<u64>> {
+        if !self.dirty {
+            return Ok(None);
+        }
+        let sealed = self.segment;
+        self.file = OpenOptions::new().create(true).append(true).open(segment_path(&self.dir, sealed + 1))?;
+        sync_dir(&self.dir)?;
+        self.segment = sealed + 1;
+        self.len = 0;
+        self.dirty = false;
+        Ok(Some(sealed))
+    }
+
+    /// Read the entries of every segment up to and including `segment`.
+    pub fn read_sealed(dir: &Path, segment: u64) -> std::io::Result<Vec<LogEntry>> {
+        let mut entries = vec![];
+        for x in segments(dir)?.into_iter().filter(|x| *x <= segment) {
+            entries.extend(read_segment(&segment_path(dir, x))?.0);
+        }
+        Ok(entries)
+    }
+
+    /// Remove every segment up to and including `segment`.
+    pub fn truncate(dir: &Path, segment: u64) -> std::io::Result<()> {
+        for x in segments(dir)?.into_iter().filter(|x| *x <= segment) {
+            std::fs::remove_file(segment_path(dir, x))?;
+        }
+        sync_dir(dir)
+    }
+}
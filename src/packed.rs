@@ -0,0 +1,504 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::chunk::CHUNK_SIZE_BYTES;
+use crate::common::sync_dir;
+use crate::chunkserver::{block_checksums, filesystem_available, storage_failed, write_file_durably, ChunkStore, ChunkserverError, CHECKSUM_BLOCK_BYTES};
+
+/// Segments are sealed, and a new one started, once they grow past this size.
+pub const SEGMENT_SIZE_BYTES: u64 = 1024 * 1024;
+
+/// Where a chunk is stored.
+struct PackedChunk {
+    version: u64,
+    segment: u64,
+    /// The offset of the chunk's record in the segment.
+    offset: u64,
+    len: u64,
+    checksums: Vec<u32>,
+}
+
+impl PackedChunk {
+    fn record_len(&self) -> u64 {
+        header_len(self.checksums.len()) + self.len
+    }
+}
+
+/// Where the latest tombstone of a deleted chunk is stored.
+struct Tombstone {
+    segment: u64,
+    offset: u64,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Segment {
+    len: u64,
+    /// Bytes of records which have been deleted or replaced.
+    garbage: u64,
+}
+
+/// Stores chunks packed into large segment files, rather than one file per chunk.
+///
+/// Chunks are appended to the last segment (`seg.{n}`) as records of a header, holding the
+/// chunk's ID, version, length and block checksums, followed by its data. The offset of each
+/// chunk is kept in memory, and rebuilt at startup by reading the record headers. When a
+/// segment is sealed, the offsets of its records are written to an index (`seg.{n}.idx`), so
+/// a damaged header can be skipped without searching the segment's data for the next record.
+/// A damaged header in the last segment, which has no index, moves the rest of the segment
+/// aside to a `.damaged` file, as the records after it may have been synced.
+///
+/// Deleting a chunk appends a tombstone record, a header with no data, which deletes the
+/// records of the chunk before it when the segments are read at startup. Once half of a sealed
+/// segment is garbage, its remaining chunks are copied to the last segment and it is removed,
+/// along with its tombstones, unless they may still delete records in older segments.
+pub struct PackedChunkStorage {
+    dir: PathBuf,
+    /// The chunks stored, by ID.
+    chunks: HashMap<u64, PackedChunk>,
+    /// The tombstones of deleted chunks which haven't been written again, by ID.
+    tombstones: HashMap<u64, Tombstone>,
+    /// Every segment, by number. The last is the one being appended to.
+    segments: BTreeMap<u64, Segment>,
+    /// The segment being appended to.
+    file: File,
+    /// The offsets of the records in the segment being appended to, indexed once it is sealed.
+    record_offsets: Vec<u64>,
+}
+
+/// A record read from a segment.
+struct Record {
+    chunk_id: u64,
+    version: u64,
+    offset: u64,
+    len: u64,
+    checksums: Vec<u32>,
+    /// Whether the record is a tombstone, deleting the chunk at or below `version`.
+    tombstone: bool,
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("seg.{segment:08}"))
+}
+
+fn index_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("seg.{segment:08}.idx"))
+}
+
+/// Encode the index of a segment: the offset of each record, then a CRC32 of the offsets.
+fn encode_index(offsets: &[u64]) -> Vec<u8> {
+    let mut index: Vec<u8> = offsets.iter().flat_map(|x| x.to_le_bytes()).collect();
+    let checksum = crc32fast::hash(&index);
+    index.extend_from_slice(&checksum.to_le_bytes());
+    index
+}
+
+/// Read the index of a segment, if it has an intact one.
+fn read_index(path: &Path) -> Option<Vec<u64>> {
+    let index = std::fs::read(path).ok()?;
+    let (offsets, checksum) = index.split_at_checked(index.len().checked_sub(4)?)?;
+    if offsets.len() % 8 != 0 || crc32fast::hash(offsets) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return None;
+    }
+    Some(offsets.chunks_exact(8).map(|x| u64::from_le_bytes(x.try_into().unwrap())).collect())
+}
+
+/// The length of a record header: the chunk ID, version, length and number of checksums,
+/// the checksums, and a CRC32 of the rest of the header.
+const fn header_len(blocks: usize) -> u64 {
+    8 + 8 + 4 + 4 + 4 * blocks as u64 + 4
+}
+
+/// The length a tombstone's header gives, as no chunk is this long.
+const TOMBSTONE_LEN: u32 = u32::MAX;
+
+/// The length of a tombstone record, which is only a header.
+const TOMBSTONE_RECORD_LEN: u64 = header_len(0);
+
+fn encode_record(chunk_id: u64, version: u64, checksums: &[u32], data: &[u8]) -> Vec<u8> {
+    encode_header(chunk_id, version, data.len() as u32, checksums, data)
+}
+
+fn encode_tombstone(chunk_id: u64, version: u64) -> Vec<u8> {
+    encode_header(chunk_id, version, TOMBSTONE_LEN, &[], &[])
+}
+
+/// Encode a record header with the given length, followed by the data.
+fn encode_header(chunk_id: u64, version: u64, len: u32, checksums: &[u32], data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(header_len(checksums.len()) as usize + data.len());
+    record.extend_from_slice(&chunk_id.to_le_bytes());
+    record.extend_from_slice(&version.to_le_bytes());
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(&(checksums.len() as u32).to_le_bytes());
+    checksums.iter().for_each(|x| record.extend_from_slice(&x.to_le_bytes()));
+    let header_checksum = crc32fast::hash(&record);
+    record.extend_from_slice(&header_checksum.to_le_bytes());
+    record.extend_from_slice(data);
+    record
+}
+
+/// The most blocks a record can have, as chunks are at most `CHUNK_SIZE_BYTES`.
+const MAX_BLOCKS: usize = CHUNK_SIZE_BYTES.div_ceil(CHECKSUM_BLOCK_BYTES);
+
+/// Parse a record header at `offset` in a segment of `file_len` bytes, if it is intact.
+fn parse_header(header: &[u8], offset: u64, file_len: u64) -> Option<Record> {
+    let chunk_id = u64::from_le_bytes(header.get(0..8)?.try_into().unwrap());
+    let version = u64::from_le_bytes(header.get(8..16)?.try_into().unwrap());
+    let len = u32::from_le_bytes(header.get(16..20)?.try_into().unwrap());
+    let blocks = u32::from_le_bytes(header.get(20..24)?.try_into().unwrap()) as usize;
+    let tombstone = len == TOMBSTONE_LEN && blocks == 0;
+    let len = if tombstone { 0 } else { len as u64 };
+    if !tombstone && (len > CHUNK_SIZE_BYTES as u64 || blocks != (len as usize).div_ceil(CHECKSUM_BLOCK_BYTES)) {
+        return None;
+    }
+    if offset + header_len(blocks) + len > file_len {
+        return None;
+    }
+
+    let checksums = header.get(24..24 + 4 * blocks)?;
+    let header_checksum = header.get(24 + 4 * blocks..28 + 4 * blocks)?;
+    if crc32fast::hash(&header[..24 + 4 * blocks]) != u32::from_le_bytes(header_checksum.try_into().unwrap()) {
+        return None;
+    }
+    let checksums = checksums.chunks_exact(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect();
+    Some(Record { chunk_id, version, offset, len, checksums, tombstone })
+}
+
+/// Read the records in a segment, returning them along with the byte ranges which were skipped.
+///
+/// A record with a damaged header is skipped to the next record in the segment's `index`.
+/// Without one, the rest of the segment is skipped: chunk data is written by clients, so
+/// searching it for an intact header could find a forged record. A skipped range running to
+/// the end of the segment may be a torn record, from a crash mid-write.
+fn read_segment(path: &Path, index: Option<&[u64]>) -> (Vec<Record>, Vec<(u64, u64)>) {
+    let file = File::open(path).unwrap();
+    let file_len = file.metadata().unwrap().len();
+    let mut reader = BufReader::new(file);
+    let mut records = vec![];
+    let mut skipped = vec![];
+    let mut offset = 0;
+    while offset < file_len {
+        let mut header = vec![0u8; 24];
+        let mut record = None;
+        if reader.read_exact(&mut header).is_ok() {
+            let blocks = u32::from_le_bytes(header[20..24].try_into().unwrap()) as usize;
+            if blocks <= MAX_BLOCKS {
+                header.resize(header_len(blocks) as usize, 0);
+                if reader.read_exact(&mut header[24..]).is_ok() {
+                    record = parse_header(&header, offset, file_len);
+                }
+            }
+        }
+
+        if let Some(record) = record {
+            reader.seek_relative(record.len as i64).unwrap();
+            offset += header_len(record.checksums.len()) + record.len;
+            records.push(record);
+            continue;
+        }
+
+        // Skip to the next record, if the segment is indexed.
+        let next = index.and_then(|index| index.iter().copied().find(|next| *next > offset && *next < file_len))
+            .unwrap_or(file_len);
+        skipped.push((offset, next));
+        reader.seek(SeekFrom::Start(next)).unwrap();
+        offset = next;
+    }
+    (records, skipped)
+}
+
+/// Move the end of a segment, from `start`, to a file of its own (`seg.{n}.{start}.damaged`),
+/// where the records in it can be recovered by hand.
+fn quarantine(dir: &Path, segment: u64, start: u64) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(segment_path(dir, segment))?;
+    let mut damaged = vec![];
+    file.seek(SeekFrom::Start(start))?;
+    file.read_to_end(&mut damaged)?;
+    write_file_durably(&dir.join(format!("seg.{segment:08}.{start}.damaged")), &damaged)?;
+    sync_dir(dir)?;
+    file.set_len(start)?;
+    file.sync_all()
+}
+
+/// Read the data of a chunk from a segment.
+fn read_data(file: &mut File, chunk: &PackedChunk) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0u8; chunk.len as usize];
+    file.seek(SeekFrom::Start(chunk.offset + header_len(chunk.checksums.len())))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+impl PackedChunkStorage {
+    /// Open the storage in `dir`, creating it if needed.
+    pub fn open(dir: PathBuf) -> PackedChunkStorage {
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let names: Vec<String> = std::fs::read_dir(&dir).unwrap()
+            .filter_map(|file| file.unwrap().file_name().into_string().ok())
+            .collect();
+        let mut numbers: Vec<u64> = names.iter()
+            .filter_map(|name| name.strip_prefix("seg.")?.parse().ok())
+            .collect();
+        numbers.sort();
+
+        let mut chunks: HashMap<u64, PackedChunk> = HashMap::new();
+        let mut tombstones: HashMap<u64, Tombstone> = HashMap::new();
+        let mut segments: BTreeMap<u64, Segment> = BTreeMap::new();
+        let mut record_offsets = vec![];
+        let last = numbers.last().copied();
+        for segment in numbers {
+            let path = segment_path(&dir, segment);
+            let index = read_index(&index_path(&dir, segment));
+            let (records, skipped) = read_segment(&path, index.as_deref());
+            let mut info = Segment { len: std::fs::metadata(&path).unwrap().len(), garbage: 0 };
+            for (start, end) in skipped {
+                if end == info.len && Some(segment) == last {
+                    // Only the last segment is appended to, so only it can end in a torn record.
+                    // It isn't indexed, so records after a damaged header can't be found safely,
+                    // but they may have been synced, so they are moved aside rather than discarded.
+                    println!("[chunkserver] quarantining torn or damaged records from offset {start} in {}", path.display());
+                    quarantine(&dir, segment, start).unwrap();
+                    info.len = start;
+                } else {
+                    // The chunks stored there are lost, and are re-replicated by the master once
+                    // it finds they're missing. The bytes are reclaimed when the segment is compacted.
+                    println!("[chunkserver] skipped {} damaged bytes at offset {start} in {}", end - start, path.display());
+                    info.garbage += end - start;
+                }
+            }
+            segments.insert(segment, info);
+            if Some(segment) == last {
+                record_offsets = records.iter().map(|record| record.offset).collect();
+            }
+
+            // Later records replace earlier ones, unless the earlier one is a newer version.
+            for Record { chunk_id, version, offset, len, checksums, tombstone } in records {
+                if tombstone {
+                    if chunks.get(&chunk_id).is_some_and(|stored| stored.version <= version) {
+                        let stored = chunks.remove(&chunk_id).unwrap();
+                        segments.get_mut(&stored.segment).unwrap().garbage += stored.record_len();
+                    }
+                    if let Some(previous) = tombstones.insert(chunk_id, Tombstone { segment, offset }) {
+                        segments.get_mut(&previous.segment).unwrap().garbage += TOMBSTONE_RECORD_LEN;
+                    }
+                    continue;
+                }
+                let chunk = PackedChunk { version, segment, offset, len, checksums };
+                match chunks.get(&chunk_id) {
+                    Some(stored) if stored.version > version => {
+                        segments.get_mut(&segment).unwrap().garbage += chunk.record_len();
+                        continue;
+                    }
+                    Some(stored) => segments.get_mut(&stored.segment).unwrap().garbage += stored.record_len(),
+                    None => {}
+                }
+                if let Some(previous) = tombstones.remove(&chunk_id) {
+                    segments.get_mut(&previous.segment).unwrap().garbage += TOMBSTONE_RECORD_LEN;
+                }
+                chunks.insert(chunk_id, chunk);
+            }
+        }
+
+        let active = segments.last_key_value().map(|(segment, _)| *segment).unwrap_or(0);
+        segments.entry(active).or_default();
+        let file = OpenOptions::new().create(true).append(true).open(segment_path(&dir, active)).unwrap();
+        // Remove the indexes of segments removed by compaction, and any half-written ones.
+        for name in names.iter() {
+            let indexed = name.strip_prefix("seg.").and_then(|x| x.strip_suffix(".idx")).and_then(|x| x.parse().ok());
+            if name.ends_with(".idx.tmp") || indexed.is_some_and(|segment| !segments.contains_key(&segment)) {
+                std::fs::remove_file(dir.join(name)).unwrap();
+            }
+        }
+        sync_dir(&dir).unwrap();
+        println!("[chunkserver] loaded {} chunks from {} segments", chunks.len(), segments.len());
+
+        let mut storage = PackedChunkStorage { dir, chunks, tombstones, segments, file, record_offsets };
+        // Finish compactions interrupted by a crash.
+        let sealed: Vec<u64> = storage.segments.keys().copied().collect();
+        sealed.into_iter().for_each(|segment| storage.maybe_compact(segment));
+        storage
+    }
+
+    /// The number of the segment being appended to.
+    fn active_segment(&self) -> u64 {
+        *self.segments.last_key_value().unwrap().0
+    }
+
+    /// Append a record to the active segment, returning where it was written.
+    ///
+    /// The record isn't durable until the segment is synced. If the write fails, whatever
+    /// part of the record was written is truncated away, so the next record follows the last.
+    fn append(&mut self, chunk_id: u64, version: u64, checksums: Vec<u32>, data: &[u8]) -> std::io::Result<PackedChunk> {
+        let record = encode_record(chunk_id, version, &checksums, data);
+        let (segment, offset) = self.append_record(&record)?;
+        Ok(PackedChunk { version, segment, offset, len: data.len() as u64, checksums })
+    }
+
+    /// Append a tombstone of a chunk to the active segment. It isn't durable until the segment is synced.
+    fn append_tombstone(&mut self, chunk_id: u64, version: u64) -> std::io::Result<()> {
+        let (segment, offset) = self.append_record(&encode_tombstone(chunk_id, version))?;
+        if let Some(previous) = self.tombstones.insert(chunk_id, Tombstone { segment, offset }) {
+            self.add_garbage(previous.segment, TOMBSTONE_RECORD_LEN);
+        }
+        Ok(())
+    }
+
+    /// Append an encoded record to the active segment, returning its segment and offset.
+    fn append_record(&mut self, record: &[u8]) -> std::io::Result<(u64, u64)> {
+        let segment = self.active_segment();
+        let offset = self.segments[&segment].len;
+        if let Err(err) = self.file.write_all(record) {
+            self.file.set_len(offset)?;
+            return Err(err);
+        }
+        self.segments.get_mut(&segment).unwrap().len += record.len() as u64;
+        self.record_offsets.push(offset);
+
+        if offset + record.len() as u64 >= SEGMENT_SIZE_BYTES {
+            // Seal and index the segment, and start appending to a new one.
+            self.file.sync_data()?;
+            write_file_durably(&index_path(&self.dir, segment), &encode_index(&self.record_offsets))?;
+            self.file = OpenOptions::new().create(true).append(true).open(segment_path(&self.dir, segment + 1))?;
+            sync_dir(&self.dir)?;
+            self.segments.insert(segment + 1, Segment::default());
+            self.record_offsets.clear();
+        }
+        Ok((segment, offset))
+    }
+
+    /// Record that `len` bytes of records in a segment are no longer needed.
+    fn add_garbage(&mut self, segment: u64, len: u64) {
+        self.segments.get_mut(&segment).unwrap().garbage += len;
+        self.maybe_compact(segment);
+    }
+
+    /// Compact a sealed segment if at least half of it is garbage.
+    ///
+    /// Compaction only removes the segment once its chunks are copied, so if it fails, the
+    /// segment is left as it is, to be compacted later.
+    fn maybe_compact(&mut self, segment: u64) {
+        let Some(&Segment { len, garbage }) = self.segments.get(&segment) else { return };
+        if segment == self.active_segment() || garbage * 2 < len {
+            return;
+        }
+        if let Err(err) = self.compact(segment, garbage) {
+            println!("[chunkserver] failed to compact segment {segment}: {err}");
+        }
+    }
+
+    fn compact(&mut self, segment: u64, garbage: u64) -> std::io::Result<()> {
+        // Copy the chunks still stored in the segment to the end of the active one.
+        let path = segment_path(&self.dir, segment);
+        let mut file = File::open(&path)?;
+        let index = read_index(&index_path(&self.dir, segment));
+        let records = read_segment(&path, index.as_deref()).0;
+        let live: Vec<u64> = records.iter()
+            .filter(|r| !r.tombstone && self.chunks.get(&r.chunk_id).is_some_and(|c| c.segment == segment && c.offset == r.offset))
+            .map(|r| r.chunk_id)
+            .collect();
+        // A tombstone is only needed while an older segment may hold a record it deletes.
+        let tombstones: Vec<(u64, u64)> = records.iter()
+            .filter(|r| r.tombstone && self.tombstones.get(&r.chunk_id).is_some_and(|t| t.segment == segment && t.offset == r.offset))
+            .map(|r| (r.chunk_id, r.version))
+            .collect();
+        let older_segments = self.segments.range(..segment).next().is_some();
+        for chunk_id in live.iter() {
+            let chunk = &self.chunks[chunk_id];
+            // The data is copied unverified, with its checksums, so any corruption is still found.
+            let data = read_data(&mut file, chunk)?;
+            let (version, checksums) = (chunk.version, chunk.checksums.clone());
+            let moved = self.append(*chunk_id, version, checksums, &data)?;
+            // The chunk is now stored twice, and the copy in this segment is garbage.
+            self.segments.get_mut(&segment).unwrap().garbage += moved.record_len();
+            self.chunks.insert(*chunk_id, moved);
+        }
+        for (chunk_id, version) in tombstones {
+            if older_segments {
+                let (moved, offset) = self.append_record(&encode_tombstone(chunk_id, version))?;
+                self.tombstones.insert(chunk_id, Tombstone { segment: moved, offset });
+            } else {
+                self.tombstones.remove(&chunk_id);
+            }
+        }
+
+        // Only remove the segment once the copies are durable.
+        self.file.sync_data()?;
+        std::fs::remove_file(&path)?;
+        // A leftover index is removed at startup.
+        let _ = std::fs::remove_file(index_path(&self.dir, segment));
+        sync_dir(&self.dir)?;
+        self.segments.remove(&segment);
+        println!("[chunkserver] compacted segment {segment}: moved {} chunks, reclaimed {garbage} bytes", live.len());
+        Ok(())
+    }
+}
+
+impl ChunkStore for PackedChunkStorage {
+    fn write_chunk(&mut self, chunk_id: u64, version: u64, data: &[u8]) -> std::io::Result<()> {
+        let chunk = self.append(chunk_id, version, block_checksums(data), data)?;
+        self.file.sync_data()?;
+        if let Some(previous) = self.chunks.insert(chunk_id, chunk) {
+            self.add_garbage(previous.segment, previous.record_len());
+        }
+        // The chunk's tombstone comes before it, so it no longer deletes anything.
+        if let Some(tombstone) = self.tombstones.remove(&chunk_id) {
+            self.add_garbage(tombstone.segment, TOMBSTONE_RECORD_LEN);
+        }
+        Ok(())
+    }
+
+    fn read_chunk(&self, chunk_id: u64) -> Result<Vec<u8>, ChunkserverError> {
+        let chunk = self.chunks.get(&chunk_id).ok_or(ChunkserverError::ChunkNotFound)?;
+        let mut file = File::open(segment_path(&self.dir, chunk.segment)).map_err(storage_failed)?;
+        let data = read_data(&mut file, chunk).map_err(storage_failed)?;
+        if block_checksums(&data) != chunk.checksums {
+            return Err(ChunkserverError::Corrupted);
+        }
+        Ok(data)
+    }
+
+    /// The chunk is copied to a new record, with its checksums, so any corruption is still found.
+    fn set_chunk_version(&mut self, chunk_id: u64, version: u64) -> std::io::Result<()> {
+        let Some(chunk) = self.chunks.get(&chunk_id) else {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "chunk not found"));
+        };
+        let data = read_data(&mut File::open(segment_path(&self.dir, chunk.segment))?, chunk)?;
+        let checksums = chunk.checksums.clone();
+        let moved = self.append(chunk_id, version, checksums, &data)?;
+        self.file.sync_data()?;
+        if let Some(previous) = self.chunks.insert(chunk_id, moved) {
+            self.add_garbage(previous.segment, previous.record_len());
+        }
+        Ok(())
+    }
+
+    /// The delete is only done once its tombstone is durable, so the chunk stays deleted after a restart.
+    fn delete_chunk(&mut self, chunk_id: u64) -> bool {
+        // Dropped first, so a compaction while appending the tombstone doesn't move it after it.
+        let Some(chunk) = self.chunks.remove(&chunk_id) else { return false };
+        if let Err(err) = self.append_tombstone(chunk_id, chunk.version).and_then(|()| self.file.sync_data()) {
+            println!("[chunkserver] failed to delete chunk {chunk_id}: {err}");
+            self.tombstones.remove(&chunk_id);
+            self.chunks.insert(chunk_id, chunk);
+            return false;
+        }
+        self.add_garbage(chunk.segment, chunk.record_len());
+        true
+    }
+
+    fn chunk_version(&self, chunk_id: u64) -> Option<u64> {
+        self.chunks.get(&chunk_id).map(|x| x.version)
+    }
+
+    fn chunk_versions(&self) -> HashMap<u64, u64> {
+        self.chunks.iter().map(|(id, x)| (*id, x.version)).collect()
+    }
+
+    fn disk_used(&self) -> u64 {
+        self.chunks.values().map(|x| x.len).sum()
+    }
+
+    fn disk_available(&self) -> std::io::Result<u64> {
+        filesystem_available(&self.dir)
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use protobuf::EnumOrUnknown;
+use crate::chunkserver::ChunkserverError;
+use crate::master::{*};
+
+// Generated from `proto/gfs.proto` by build.rs.
+include!(concat!(env!("OUT_DIR"), "/proto/mod.rs"));
+
+/// A message which decoded as protobuf but does not describe a valid value.
+#[derive(Debug, Clone)]
+pub struct InvalidMessage(pub &'static str);
+
+impl From<InvalidMessage> for std::io::Error {
+    fn from(err: InvalidMessage) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err.0)
+    }
+}
+
+fn to_chunk_hash(bytes: &[u8]) -> Result<[u8; 32], InvalidMessage> {
+    bytes.try_into().map_err(|_| InvalidMessage("chunk hash must be 32 bytes"))
+}
+
+//
+// Errors.
+//
+
+impl From<&MasterError> for gfs::MasterError {
+    fn from(err: &MasterError) -> gfs::MasterError {
+        match err {
+            MasterError::FileNotFound => gfs::MasterError::MASTER_ERROR_FILE_NOT_FOUND,
+            MasterError::EndOfFile => gfs::MasterError::MASTER_ERROR_END_OF_FILE,
+            MasterError::ChunkNotFound => gfs::MasterError::MASTER_ERROR_CHUNK_NOT_FOUND,
+            MasterError::ChunkserverNotFound => gfs::MasterError::MASTER_ERROR_CHUNKSERVER_NOT_FOUND,
+            MasterError::FileExists => gfs::MasterError::MASTER_ERROR_FILE_EXISTS,
+            MasterError::InvalidPath => gfs::MasterError::MASTER_ERROR_INVALID_PATH,
+            MasterError::DirectoryNotEmpty => gfs::MasterError::MASTER_ERROR_DIRECTORY_NOT_EMPTY,
+            MasterError::LogFailed => gfs::MasterError::MASTER_ERROR_LOG_FAILED,
+            MasterError::Unavailable => gfs::MasterError::MASTER_ERROR_UNAVAILABLE,
+            MasterError::AppendFailed => gfs::MasterError::MASTER_ERROR_APPEND_FAILED,
+        }
+    }
+}
+
+impl From<EnumOrUnknown<gfs::MasterError>> for MasterError {
+    fn from(err: EnumOrUnknown<gfs::MasterError>) -> MasterError {
+        match err.enum_value() {
+            Ok(gfs::MasterError::MASTER_ERROR_FILE_NOT_FOUND) => MasterError::FileNotFound,
+            Ok(gfs::MasterError::MASTER_ERROR_END_OF_FILE) => MasterError::EndOfFile,
+            Ok(gfs::MasterError::MASTER_ERROR_CHUNK_NOT_FOUND) => MasterError::ChunkNotFound,
+            Ok(gfs::MasterError::MASTER_ERROR_CHUNKSERVER_NOT_FOUND) => MasterError::ChunkserverNotFound,
+            Ok(gfs::MasterError::MASTER_ERROR_FILE_EXISTS) => MasterError::FileExists,
+            Ok(gfs::MasterError::MASTER_ERROR_INVALID_PATH) => MasterError::InvalidPath,
+            Ok(gfs::MasterError::MASTER_ERROR_DIRECTORY_NOT_EMPTY) => MasterError::DirectoryNotEmpty,
+            Ok(gfs::MasterError::MASTER_ERROR_LOG_FAILED) => MasterError::LogFailed,
+            Ok(gfs::MasterError::MASTER_ERROR_APPEND_FAILED) => MasterError::AppendFailed,
+            // Errors added by newer masters are treated as the master being unavailable.
+            _ => MasterError::Unavailable,
+        }
+    }
+}
+
+impl From<&Result<(), MasterError>> for gfs::MasterResult {
+    fn from(res: &Result<(), MasterError>) -> gfs::MasterResult {
+        let mut msg = gfs::MasterResult::new();
+        if let Err(err) = res {
+            msg.error = gfs::MasterError::from(err).into();
+        }
+        msg
+    }
+}
+
+impl From<gfs::MasterResult> for Result<(), MasterError> {
+    fn from(msg: gfs::MasterResult) -> Result<(), MasterError> {
+        match msg.error.enum_value() {
+            Ok(gfs::MasterError::MASTER_ERROR_UNSPECIFIED) => Ok(()),
+            _ => Err(msg.error.into()),
+        }
+    }
+}
+
+impl From<&ChunkserverError> for gfs::ChunkserverError {
+    fn from(err: &ChunkserverError) -> gfs::ChunkserverError {
+        match err {
+            ChunkserverError::InvalidChunkLength => gfs::ChunkserverError::CHUNKSERVER_ERROR_INVALID_CHUNK_LENGTH,
+            ChunkserverError::ChunkNotFound => gfs::ChunkserverError::CHUNKSERVER_ERROR_CHUNK_NOT_FOUND,
+            ChunkserverError::Unavailable => gfs::ChunkserverError::CHUNKSERVER_ERROR_UNAVAILABLE,
+            ChunkserverError::Corrupted => gfs::ChunkserverError::CHUNKSERVER_ERROR_CORRUPTED,
+            ChunkserverError::StorageFailed => gfs::ChunkserverError::CHUNKSERVER_ERROR_STORAGE_FAILED,
+        }
+    }
+}
+
+impl From<&gfs::ChunkserverResult> for Result<Vec<u8>, ChunkserverError> {
+    fn from(res: &gfs::ChunkserverResult) -> Result<Vec<u8>, ChunkserverError> {
+        match res.error.enum_value() {
+            Ok(gfs::ChunkserverError::CHUNKSERVER_ERROR_UNSPECIFIED) => Ok(res.data.clone()),
+            Ok(gfs::ChunkserverError::CHUNKSERVER_ERROR_INVALID_CHUNK_LENGTH) => Err(ChunkserverError::InvalidChunkLength),
+            Ok(gfs::ChunkserverError::CHUNKSERVER_ERROR_CHUNK_NOT_FOUND) => Err(ChunkserverError::ChunkNotFound),
+            Ok(gfs::ChunkserverError::CHUNKSERVER_ERROR_CORRUPTED) => Err(ChunkserverError::Corrupted),
+            Ok(gfs::ChunkserverError::CHUNKSERVER_ERROR_STORAGE_FAILED) => Err(ChunkserverError::StorageFailed),
+            // Errors added by newer peers are treated as the chunkserver being unavailable.
+            _ => Err(ChunkserverError::Unavailable),
+        }
+    }
+}
+
+impl From<Result<Vec<u8>, ChunkserverError>> for gfs::ChunkserverResult {
+    fn from(res: Result<Vec<u8>, ChunkserverError>) -> gfs::ChunkserverResult {
+        let mut msg = gfs::ChunkserverResult::new();
+        match res {
+            Ok(data) => msg.data = data,
+            Err(err) => msg.error = gfs::ChunkserverError::from(&err).into(),
+        }
+        msg
+    }
+}
+
+//
+// Master types.
+//
+
+impl From<&AppendOperation> for gfs::AppendOperation {
+    fn from(op: &AppendOperation) -> gfs::AppendOperation {
+        let mut msg = gfs::AppendOperation::new();
+        msg.file_path = op.file_path.clone();
+        msg.chunk_sequence = op.chunk_sequence.iter().map(|hash| hash.to_vec()).collect();
+        msg.chunk_locations = op.chunk_locations.iter().map(|(hash, locations)| {
+            let mut msg = gfs::ChunkLocations::new();
+            msg.chunk_hash = hash.to_vec();
+            msg.locations = locations.clone();
+            msg
+        }).collect();
+        msg.length = op.length;
+        msg
+    }
+}
+
+impl TryFrom<gfs::AppendOperation> for AppendOperation {
+    type Error = InvalidMessage;
+
+    fn try_from(msg: gfs::AppendOperation) -> Result<AppendOperation, InvalidMessage> {
+        let chunk_sequence = msg.chunk_sequence.iter()
+            .map(|hash| to_chunk_hash(hash))
+            .collect::<Result<Vec<_>, _>>()?;
+        let chunk_locations = msg.chunk_locations.into_iter()
+            .map(|x| Ok((to_chunk_hash(&x.chunk_hash)?, x.locations)))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        Ok(AppendOperation { file_path: msg.file_path, chunk_sequence, chunk_locations, length: msg.length })
+    }
+}
+
+impl From<&ChunkRead> for gfs::ChunkRead {
+    fn from(read: &ChunkRead) -> gfs::ChunkRead {
+        let mut msg = gfs::ChunkRead::new();
+        msg.chunk_id = read.chunk_id;
+        msg.locations = read.locations.clone();
+        msg.offset = read.offset;
+        msg.length = read.length;
+        msg
+    }
+}
+
+impl From<gfs::ChunkRead> for ChunkRead {
+    fn from(msg: gfs::ChunkRead) -> ChunkRead {
+        ChunkRead { chunk_id: msg.chunk_id, locations: msg.locations, offset: msg.offset, length: msg.length }
+    }
+}
+
+impl From<&ReadOperationInfo> for gfs::ReadOperationInfo {
+    fn from(info: &ReadOperationInfo) -> gfs::ReadOperationInfo {
+        let mut msg = gfs::ReadOperationInfo::new();
+        msg.path = info.path.clone();
+        msg.offset = info.offset;
+        msg.length = info.length;
+        msg.chunk_reads = info.chunk_reads.iter().map(gfs::ChunkRead::from).collect();
+        msg
+    }
+}
+
+impl From<gfs::ReadOperationInfo> for ReadOperationInfo {
+    fn from(msg: gfs::ReadOperationInfo) -> ReadOperationInfo {
+        ReadOperationInfo {
+            path: msg.path,
+            offset: msg.offset,
+            length: msg.length,
+            chunk_reads: msg.chunk_reads.into_iter().map(ChunkRead::from).collect(),
+        }
+    }
+}
+
+impl From<&StatInfo> for gfs::StatInfo {
+    fn from(info: &StatInfo) -> gfs::StatInfo {
+        let mut msg = gfs::StatInfo::new();
+        msg.length = info.length;
+        msg
+    }
+}
+
+impl From<gfs::StatInfo> for StatInfo {
+    fn from(msg: gfs::StatInfo) -> StatInfo {
+        StatInfo { length: msg.length }
+    }
+}
+
+impl From<&Heartbeat> for gfs::Heartbeat {
+    fn from(heartbeat: &Heartbeat) -> gfs::Heartbeat {
+        let mut msg = gfs::Heartbeat::new();
+        msg.chunkserver_id = heartbeat.chunkserver_id.clone();
+        msg.disk_used = heartbeat.disk_used;
+        msg.disk_free = heartbeat.disk_free;
+        msg.full_report = heartbeat.full_report;
+        msg.added_chunks = heartbeat.added_chunks.clone();
+        msg.removed_chunks = heartbeat.removed_chunks.clone();
+        msg.corrupt_chunks = heartbeat.corrupt_chunks.clone();
+        msg
+    }
+}
+
+impl From<gfs::Heartbeat> for Heartbeat {
+    fn from(msg: gfs::Heartbeat) -> Heartbeat {
+        Heartbeat {
+            chunkserver_id: msg.chunkserver_id,
+            disk_used: msg.disk_used,
+            disk_free: msg.disk_free,
+            full_report: msg.full_report,
+            added_chunks: msg.added_chunks,
+            removed_chunks: msg.removed_chunks,
+            corrupt_chunks: msg.corrupt_chunks,
+        }
+    }
+}
+
+impl From<&HeartbeatResponse> for gfs::HeartbeatResponse {
+    fn from(res: &HeartbeatResponse) -> gfs::HeartbeatResponse {
+        let mut msg = gfs::HeartbeatResponse::new();
+        msg.full_report_needed = res.full_report_needed;
+        msg.delete_chunks = res.delete_chunks.clone();
+        msg
+    }
+}
+
+impl From<gfs::HeartbeatResponse> for HeartbeatResponse {
+    fn from(msg: gfs::HeartbeatResponse) -> HeartbeatResponse {
+        HeartbeatResponse {
+            full_report_needed: msg.full_report_needed,
+            delete_chunks: msg.delete_chunks,
+        }
+    }
+}
@@ -0,0 +1,782 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use protobuf::Message;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::chunk::ChunkHash;
+use crate::chunkserver::ChunkserverError;
+use crate::common::{*};
+use crate::master::{*};
+use crate::proto::gfs;
+use crate::proto::gfs::master_request::Request as MasterRequest;
+use crate::proto::gfs::master_response::Response as MasterResponse;
+use crate::proto::gfs::chunkserver_request::Request as ChunkserverRequest;
+use crate::proto::gfs::chunkserver_response::Response as ChunkserverResponse;
+use crate::proto::InvalidMessage;
+
+//
+// TCP RPC transport.
+//
+// Every call is a single request frame followed by a single response frame on a
+// fresh TCP connection. A frame is a 4-byte big-endian length followed by a
+// protobuf message from `proto/gfs.proto`.
+//
+// Every connect, read and write is bounded by a timeout, so a hung peer is reported as
+// unavailable rather than blocking the caller forever.
+//
+
+/// Frames larger than this are rejected, so a bad peer can't make us allocate unbounded memory.
+const MAX_FRAME_BYTES: u32 = 64 * 1024 * 1024;
+
+/// How long a call to a chunkserver, or connecting to any peer, can take.
+pub const RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a call to the master can take. This is longer than `RPC_TIMEOUT`, as an append
+/// waits for the master to commit its chunks, and a hung chunkserver holds that up.
+pub const MASTER_RPC_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The most connections a server handles at once. Further connections wait to be accepted.
+pub const MAX_CONNECTIONS: usize = 64;
+
+/// How long to wait before accepting again after a failure, which may persist (e.g. running
+/// out of file descriptors).
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+fn write_frame<M: Message>(stream: &mut TcpStream, msg: &M) -> std::io::Result<()> {
+    let body = msg.write_to_bytes()?;
+    stream.write_all(&(body.len() as u32).to_be_bytes())?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+fn read_frame<M: Message>(stream: &mut TcpStream) -> std::io::Result<M> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_BYTES {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut body = vec![0u8; len as usize];
+    stream.read_exact(&mut body)?;
+    Ok(M::parse_from_bytes(&body)?)
+}
+
+fn timed_out() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out")
+}
+
+/// Connect to `addr`, trying each address it resolves to for at most `RPC_TIMEOUT`.
+fn connect(addr: &str) -> std::io::Result<TcpStream> {
+    let mut res = Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "address resolved to nothing"));
+    for addr in addr.to_socket_addrs()? {
+        res = TcpStream::connect_timeout(&addr, RPC_TIMEOUT);
+        if res.is_ok() {
+            break;
+        }
+    }
+    res
+}
+
+/// Make a call, failing if it takes longer than `timeout` to send the request or get the response.
+fn call<Req: Message, Res: Message>(addr: &str, req: &Req, timeout: Duration) -> std::io::Result<Res> {
+    let mut stream = connect(addr)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write_frame(&mut stream, req)?;
+    read_frame(&mut stream)
+}
+
+/// Counts the connections a server is handling, so it stops accepting at `MAX_CONNECTIONS`.
+#[derive(Default)]
+struct ConnectionLimit {
+    active: Mutex<usize>,
+    finished: Condvar,
+}
+
+impl ConnectionLimit {
+    /// Wait until fewer than `MAX_CONNECTIONS` connections are being handled, and count another.
+    fn acquire(self: &Arc<Self>) -> ConnectionSlot {
+        let active = self.active.lock().unwrap();
+        let mut active = self.finished.wait_while(active, |active| *active >= MAX_CONNECTIONS).unwrap();
+        *active += 1;
+        ConnectionSlot(self.clone())
+    }
+}
+
+/// A connection counted by a `ConnectionLimit`, until it is dropped.
+struct ConnectionSlot(Arc<ConnectionLimit>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        *self.0.active.lock().unwrap() -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+/// Accept connections on `addr` and answer each request frame with `handle`.
+///
+/// Every connection is served on its own thread, up to `MAX_CONNECTIONS` at once, and is
+/// dropped if a request is malformed, or the peer goes quiet for `RPC_TIMEOUT`.
+fn serve<Req, Res, F>(addr: &str, name: &'static str, handle: F) -> std::io::Result<JoinHandle<()>>
+where
+    Req: Message,
+    Res: Message,
+    F: Fn(Req) -> Result<Res, InvalidMessage> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    let handle = Arc::new(handle);
+    println!("[{name}] listening on {}", listener.local_addr()?);
+
+    let limit = Arc::new(ConnectionLimit::default());
+    Ok(std::thread::spawn(move || {
+        loop {
+            let slot = limit.acquire();
+            let mut stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    println!("[{name}] failed to accept a connection: {err}");
+                    std::thread::sleep(ACCEPT_RETRY_DELAY);
+                    continue;
+                }
+            };
+            let handle = handle.clone();
+            std::thread::spawn(move || {
+                let _slot = slot;
+                let _ = stream.set_nodelay(true);
+                let _ = stream.set_read_timeout(Some(RPC_TIMEOUT));
+                let _ = stream.set_write_timeout(Some(RPC_TIMEOUT));
+                // Serve requests until the peer hangs up.
+                while let Ok(req) = read_frame::<Req>(&mut stream) {
+                    let res = match handle(req) {
+                        Ok(res) => res,
+                        Err(err) => {
+                            println!("[{name}] bad request: {}", err.0);
+                            break;
+                        }
+                    };
+                    if write_frame(&mut stream, &res).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }))
+}
+
+fn bad_response() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "unexpected response")
+}
+
+
+//
+// Chunkserver RPC.
+//
+
+/// Serve a chunkserver's RPC interface on `addr`.
+pub fn serve_chunkserver(chunkserver: Arc<dyn ChunkserverHandle>, addr: &str) -> std::io::Result<JoinHandle<()>> {
+    serve(addr, "chunkserver", move |req: gfs::ChunkserverRequest| {
+        let req = req.request.ok_or(InvalidMessage("empty request"))?;
+        let res = match req {
+            ChunkserverRequest::PushChunk(req) => {
+                ChunkserverResponse::PushChunk(chunkserver.push_chunk(&req.data).map(|_| vec![]).into())
+            }
+            ChunkserverRequest::CommitChunk(req) => {
+                let chunk_hash = req.chunk_hash.as_slice().try_into().map_err(|_| InvalidMessage("chunk hash must be 32 bytes"))?;
+                ChunkserverResponse::CommitChunk(chunkserver.commit_chunk(chunk_hash, req.chunk_id, req.version).map(|_| vec![]).into())
+            }
+            ChunkserverRequest::ReadChunk(req) => {
+                ChunkserverResponse::ReadChunk(chunkserver.read_chunk(req.chunk_id).into())
+            }
+            ChunkserverRequest::ReplicateChunk(req) => {
+                ChunkserverResponse::ReplicateChunk(chunkserver.replicate_chunk(req.chunk_id, req.version, &req.source).map(|_| vec![]).into())
+            }
+            ChunkserverRequest::UpdateChunkVersion(req) => {
+                ChunkserverResponse::UpdateChunkVersion(chunkserver.update_chunk_version(req.chunk_id, req.version).map(|_| vec![]).into())
+            }
+        };
+        let mut msg = gfs::ChunkserverResponse::new();
+        msg.response = Some(res);
+        Ok(msg)
+    })
+}
+
+/// A stub for a chunkserver in another process.
+#[derive(Debug, Clone)]
+pub struct RemoteChunkserver {
+    addr: String,
+}
+
+impl RemoteChunkserver {
+    pub fn new(addr: &str) -> RemoteChunkserver {
+        RemoteChunkserver { addr: addr.to_string() }
+    }
+
+    fn call(&self, req: ChunkserverRequest) -> Result<Vec<u8>, ChunkserverError> {
+        chunkserver_result(&self.addr, call(&self.addr, &chunkserver_request(req), RPC_TIMEOUT))
+    }
+}
+
+fn chunkserver_request(req: ChunkserverRequest) -> gfs::ChunkserverRequest {
+    let mut msg = gfs::ChunkserverRequest::new();
+    msg.request = Some(req);
+    msg
+}
+
+/// Read the result of a call to the chunkserver at `addr`.
+fn chunkserver_result(addr: &str, res: std::io::Result<gfs::ChunkserverResponse>) -> Result<Vec<u8>, ChunkserverError> {
+    let res = res.and_then(|res| match res.response {
+        Some(ChunkserverResponse::PushChunk(res))
+        | Some(ChunkserverResponse::CommitChunk(res))
+        | Some(ChunkserverResponse::ReadChunk(res))
+        | Some(ChunkserverResponse::ReplicateChunk(res))
+        | Some(ChunkserverResponse::UpdateChunkVersion(res)) => Ok(res),
+        None => Err(bad_response()),
+    });
+
+    match res {
+        Ok(res) => (&res).into(),
+        Err(err) => {
+            println!("[rpc] chunkserver {addr} unreachable: {err}");
+            Err(ChunkserverError::Unavailable)
+        }
+    }
+}
+
+fn push_chunk_request(data: &[u8]) -> ChunkserverRequest {
+    let mut req = gfs::PushChunkRequest::new();
+    req.data = data.to_vec();
+    ChunkserverRequest::PushChunk(req)
+}
+
+fn read_chunk_request(chunk_id: u64) -> ChunkserverRequest {
+    let mut req = gfs::ReadChunkRequest::new();
+    req.chunk_id = chunk_id;
+    ChunkserverRequest::ReadChunk(req)
+}
+
+impl ChunkserverHandle for RemoteChunkserver {
+    fn push_chunk(&self, data: &[u8]) -> Result<(), ChunkserverError> {
+        self.call(push_chunk_request(data)).map(|_| ())
+    }
+
+    fn commit_chunk(&self, chunk_hash: ChunkHash, chunk_id: u64, version: u64) -> Result<(), ChunkserverError> {
+        let mut req = gfs::CommitChunkRequest::new();
+        req.chunk_hash = chunk_hash.to_vec();
+        req.chunk_id = chunk_id;
+        req.version = version;
+        self.call(ChunkserverRequest::CommitChunk(req)).map(|_| ())
+    }
+
+    fn read_chunk(&self, chunk_id: u64) -> Result<Vec<u8>, ChunkserverError> {
+        self.call(read_chunk_request(chunk_id))
+    }
+
+    fn replicate_chunk(&self, chunk_id: u64, version: u64, source: &str) -> Result<(), ChunkserverError> {
+        let mut req = gfs::ReplicateChunkRequest::new();
+        req.chunk_id = chunk_id;
+        req.version = version;
+        req.source = source.to_string();
+        self.call(ChunkserverRequest::ReplicateChunk(req)).map(|_| ())
+    }
+
+    fn update_chunk_version(&self, chunk_id: u64, version: u64) -> Result<(), ChunkserverError> {
+        let mut req = gfs::UpdateChunkVersionRequest::new();
+        req.chunk_id = chunk_id;
+        req.version = version;
+        self.call(ChunkserverRequest::UpdateChunkVersion(req)).map(|_| ())
+    }
+}
+
+
+//
+// Master RPC.
+//
+
+fn path_list(paths: Vec<String>) -> gfs::PathList {
+    let mut msg = gfs::PathList::new();
+    msg.paths = paths;
+    msg
+}
+
+fn path_request(path: &str) -> gfs::PathRequest {
+    let mut msg = gfs::PathRequest::new();
+    msg.path = path.to_string();
+    msg
+}
+
+/// Calls with no error in their response, which fail behind this server, drop the
+/// connection, so the caller sees the master as unavailable.
+fn unavailable(_: MasterError) -> InvalidMessage {
+    InvalidMessage("master unavailable")
+}
+
+/// Serve the master's RPC interface on `addr`.
+pub fn serve_master(master: Arc<dyn MasterHandle>, addr: &str) -> std::io::Result<JoinHandle<()>> {
+    serve(addr, "master", move |req: gfs::MasterRequest| {
+        let req = req.request.ok_or(InvalidMessage("empty request"))?;
+        let res = match req {
+            MasterRequest::Heartbeat(heartbeat) => {
+                MasterResponse::Heartbeat((&master.receive_heartbeat(heartbeat.into())).into())
+            }
+            MasterRequest::AppendFile(op) => MasterResponse::AppendFile((&master.append_file(op.try_into()?)).into()),
+            MasterRequest::GetFreeChunkservers(req) => {
+                let replication_factor = req.replication_factor.try_into().map_err(|_| InvalidMessage("replication factor too large"))?;
+                MasterResponse::GetFreeChunkservers(path_list(master.get_free_chunkservers(req.num_chunks, replication_factor).map_err(unavailable)?))
+            }
+            MasterRequest::GetReadInfos(req) => {
+                let mut res = gfs::GetReadInfosResponse::new();
+                res.result = Some(match master.get_read_infos(&req.path, req.offset, req.length) {
+                    Ok(info) => gfs::get_read_infos_response::Result::Ok((&info).into()),
+                    Err(err) => gfs::get_read_infos_response::Result::Error(gfs::MasterError::from(&err).into()),
+                });
+                MasterResponse::GetReadInfos(res)
+            }
+            MasterRequest::Stat(req) => {
+                let mut res = gfs::StatResponse::new();
+                res.result = Some(match master.stat(&req.path) {
+                    Ok(info) => gfs::stat_response::Result::Ok((&info).into()),
+                    Err(err) => gfs::stat_response::Result::Error(gfs::MasterError::from(&err).into()),
+                });
+                MasterResponse::Stat(res)
+            }
+            MasterRequest::Ls(req) => MasterResponse::Ls(path_list(master.ls(&req.path).map_err(unavailable)?)),
+            MasterRequest::LsTree(req) => MasterResponse::LsTree(path_list(master.ls_tree(&req.path).map_err(unavailable)?)),
+            MasterRequest::Df(_) => MasterResponse::Df(master.df().map_err(unavailable)?),
+            MasterRequest::Du(_) => MasterResponse::Du(master.du().map_err(unavailable)?),
+            MasterRequest::Delete(req) => MasterResponse::Delete((&master.delete(&req.path)).into()),
+            MasterRequest::Undelete(req) => MasterResponse::Undelete((&master.undelete(&req.path)).into()),
+            MasterRequest::Rename(req) => MasterResponse::Rename((&master.rename(&req.from, &req.to, req.overwrite)).into()),
+            MasterRequest::Mkdir(req) => MasterResponse::Mkdir((&master.mkdir(&req.path)).into()),
+            MasterRequest::Rmdir(req) => MasterResponse::Rmdir((&master.rmdir(&req.path)).into()),
+            MasterRequest::Snapshot(req) => MasterResponse::Snapshot((&master.snapshot(&req.from, &req.to)).into()),
+        };
+        let mut msg = gfs::MasterResponse::new();
+        msg.response = Some(res);
+        Ok(msg)
+    })
+}
+
+/// A stub for a master in another process.
+///
+/// An unreachable master is reported as `MasterError::Unavailable`.
+#[derive(Debug, Clone)]
+pub struct RemoteMaster {
+    addr: String,
+}
+
+impl RemoteMaster {
+    pub fn new(addr: &str) -> RemoteMaster {
+        RemoteMaster { addr: addr.to_string() }
+    }
+
+    fn call(&self, req: MasterRequest) -> Result<MasterResponse, MasterError> {
+        master_response(&self.addr, call(&self.addr, &master_request(req), MASTER_RPC_TIMEOUT))
+    }
+
+    fn run<T>(&self, call: MasterCall<T>) -> Result<T, MasterError> {
+        (call.parse)(self.call(call.req)?)
+    }
+}
+
+fn master_request(req: MasterRequest) -> gfs::MasterRequest {
+    let mut msg = gfs::MasterRequest::new();
+    msg.request = Some(req);
+    msg
+}
+
+/// Read the response to a call to the master at `addr`.
+fn master_response(addr: &str, res: std::io::Result<gfs::MasterResponse>) -> Result<MasterResponse, MasterError> {
+    res.and_then(|res| res.response.ok_or_else(bad_response)).map_err(|err| {
+        println!("[rpc] master {addr} unreachable: {err}");
+        MasterError::Unavailable
+    })
+}
+
+fn unexpected_response(res: impl std::fmt::Debug) -> MasterError {
+    println!("[rpc] unexpected response from master: {res:?}");
+    MasterError::Unavailable
+}
+
+/// A call to the master, as its request and how to read its response.
+/// Shared by `RemoteMaster` and `AsyncRemoteMaster`.
+struct MasterCall<T> {
+    req: MasterRequest,
+    parse: fn(MasterResponse) -> Result<T, MasterError>,
+}
+
+fn append_file_call(op: &AppendOperation) -> MasterCall<()> {
+    MasterCall {
+        req: MasterRequest::AppendFile(op.into()),
+        parse: |res| match res {
+            MasterResponse::AppendFile(res) => res.into(),
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+fn get_free_chunkservers_call(num_chunks: u64, replication_factor: u8) -> MasterCall<Vec<String>> {
+    let mut req = gfs::GetFreeChunkserversRequest::new();
+    req.num_chunks = num_chunks;
+    req.replication_factor = replication_factor as u32;
+    MasterCall {
+        req: MasterRequest::GetFreeChunkservers(req),
+        parse: |res| match res {
+            MasterResponse::GetFreeChunkservers(res) => Ok(res.paths),
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+fn get_read_infos_call(path: &str, offset: u64, length: u64) -> MasterCall<ReadOperationInfo> {
+    let mut req = gfs::GetReadInfosRequest::new();
+    req.path = path.to_string();
+    req.offset = offset;
+    req.length = length;
+    MasterCall {
+        req: MasterRequest::GetReadInfos(req),
+        parse: |res| match res {
+            MasterResponse::GetReadInfos(res) => match res.result {
+                Some(gfs::get_read_infos_response::Result::Ok(info)) => Ok(info.into()),
+                Some(gfs::get_read_infos_response::Result::Error(err)) => Err(err.into()),
+                None => Err(unexpected_response(res.result)),
+            },
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+fn stat_call(path: &str) -> MasterCall<StatInfo> {
+    MasterCall {
+        req: MasterRequest::Stat(path_request(path)),
+        parse: |res| match res {
+            MasterResponse::Stat(res) => match res.result {
+                Some(gfs::stat_response::Result::Ok(info)) => Ok(info.into()),
+                Some(gfs::stat_response::Result::Error(err)) => Err(err.into()),
+                None => Err(unexpected_response(res.result)),
+            },
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+fn ls_call(path: &str) -> MasterCall<Vec<String>> {
+    MasterCall {
+        req: MasterRequest::Ls(path_request(path)),
+        parse: |res| match res {
+            MasterResponse::Ls(res) => Ok(res.paths),
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+fn ls_tree_call(path: &str) -> MasterCall<Vec<String>> {
+    MasterCall {
+        req: MasterRequest::LsTree(path_request(path)),
+        parse: |res| match res {
+            MasterResponse::LsTree(res) => Ok(res.paths),
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+fn df_call() -> MasterCall<u64> {
+    MasterCall {
+        req: MasterRequest::Df(gfs::Empty::new()),
+        parse: |res| match res {
+            MasterResponse::Df(res) => Ok(res),
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+fn du_call() -> MasterCall<u64> {
+    MasterCall {
+        req: MasterRequest::Du(gfs::Empty::new()),
+        parse: |res| match res {
+            MasterResponse::Du(res) => Ok(res),
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+fn delete_call(path: &str) -> MasterCall<()> {
+    MasterCall {
+        req: MasterRequest::Delete(path_request(path)),
+        parse: |res| match res {
+            MasterResponse::Delete(res) => res.into(),
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+fn undelete_call(path: &str) -> MasterCall<()> {
+    MasterCall {
+        req: MasterRequest::Undelete(path_request(path)),
+        parse: |res| match res {
+            MasterResponse::Undelete(res) => res.into(),
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+fn rename_call(from: &str, to: &str, overwrite: bool) -> MasterCall<()> {
+    let mut req = gfs::RenameRequest::new();
+    req.from = from.to_string();
+    req.to = to.to_string();
+    req.overwrite = overwrite;
+    MasterCall {
+        req: MasterRequest::Rename(req),
+        parse: |res| match res {
+            MasterResponse::Rename(res) => res.into(),
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+fn mkdir_call(path: &str) -> MasterCall<()> {
+    MasterCall {
+        req: MasterRequest::Mkdir(path_request(path)),
+        parse: |res| match res {
+            MasterResponse::Mkdir(res) => res.into(),
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+fn rmdir_call(path: &str) -> MasterCall<()> {
+    MasterCall {
+        req: MasterRequest::Rmdir(path_request(path)),
+        parse: |res| match res {
+            MasterResponse::Rmdir(res) => res.into(),
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+fn snapshot_call(from: &str, to: &str) -> MasterCall<()> {
+    let mut req = gfs::SnapshotRequest::new();
+    req.from = from.to_string();
+    req.to = to.to_string();
+    MasterCall {
+        req: MasterRequest::Snapshot(req),
+        parse: |res| match res {
+            MasterResponse::Snapshot(res) => res.into(),
+            res => Err(unexpected_response(res)),
+        },
+    }
+}
+
+impl MasterHandle for RemoteMaster {
+    fn receive_heartbeat(&self, heartbeat: Heartbeat) -> HeartbeatResponse {
+        match self.call(MasterRequest::Heartbeat((&heartbeat).into())) {
+            Ok(MasterResponse::Heartbeat(res)) => res.into(),
+            res => {
+                println!("[rpc] heartbeat to master {} failed: {:?}", self.addr, res.err());
+                // The master may have missed the chunks reported, so send them all next time.
+                HeartbeatResponse { full_report_needed: true, delete_chunks: HashMap::new() }
+            }
+        }
+    }
+
+    fn append_file(&self, op: AppendOperation) -> Result<(), MasterError> {
+        self.run(append_file_call(&op))
+    }
+
+    fn get_free_chunkservers(&self, num_chunks: u64, replication_factor: u8) -> Result<Vec<String>, MasterError> {
+        self.run(get_free_chunkservers_call(num_chunks, replication_factor))
+    }
+
+    fn get_read_infos(&self, path: &str, offset: u64, length: u64) -> Result<ReadOperationInfo, MasterError> {
+        self.run(get_read_infos_call(path, offset, length))
+    }
+
+    fn stat(&self, path: &str) -> Result<StatInfo, MasterError> {
+        self.run(stat_call(path))
+    }
+
+    fn ls(&self, path: &str) -> Result<Vec<String>, MasterError> {
+        self.run(ls_call(path))
+    }
+
+    fn ls_tree(&self, path: &str) -> Result<Vec<String>, MasterError> {
+        self.run(ls_tree_call(path))
+    }
+
+    fn df(&self) -> Result<u64, MasterError> {
+        self.run(df_call())
+    }
+
+    fn du(&self) -> Result<u64, MasterError> {
+        self.run(du_call())
+    }
+
+    fn delete(&self, path: &str) -> Result<(), MasterError> {
+        self.run(delete_call(path))
+    }
+
+    fn undelete(&self, path: &str) -> Result<(), MasterError> {
+        self.run(undelete_call(path))
+    }
+
+    fn rename(&self, from: &str, to: &str, overwrite: bool) -> Result<(), MasterError> {
+        self.run(rename_call(from, to, overwrite))
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), MasterError> {
+        self.run(mkdir_call(path))
+    }
+
+    fn rmdir(&self, path: &str) -> Result<(), MasterError> {
+        self.run(rmdir_call(path))
+    }
+
+    fn snapshot(&self, from: &str, to: &str) -> Result<(), MasterError> {
+        self.run(snapshot_call(from, to))
+    }
+}
+
+/// A transport where chunkservers run in other processes and are reached over TCP.
+///
+/// Chunkservers registered by heartbeat are assumed to use their `host:port` listen address
+/// as their ID. Others are unknown, unless added with an address, or the transport is
+/// `unrestricted`.
+#[derive(Default)]
+pub struct TcpTransport {
+    addrs: HashMap<String, String>,
+    /// Whether to reach unknown chunkservers at their ID.
+    unrestricted: bool,
+}
+
+impl TcpTransport {
+    /// A transport for the master, which only reaches chunkservers which have registered.
+    pub fn new() -> TcpTransport {
+        TcpTransport::default()
+    }
+
+    /// A transport for clients and chunkservers, which reaches any chunkserver at its ID, as
+    /// they only use IDs handed out by the master.
+    pub fn unrestricted() -> TcpTransport {
+        TcpTransport { unrestricted: true, ..TcpTransport::default() }
+    }
+
+    /// Register a chunkserver listening on `addr`.
+    pub fn add_node(&mut self, id: &str, addr: &str) {
+        self.addrs.insert(id.to_string(), addr.to_string());
+    }
+}
+
+impl Transport for TcpTransport {
+    fn get_node(&self, id: &str) -> Option<Arc<dyn ChunkserverHandle>> {
+        let addr = match self.addrs.get(id) {
+            Some(addr) => addr.as_str(),
+            None if self.unrestricted => id,
+            None => return None,
+        };
+        Some(Arc::new(RemoteChunkserver::new(addr)))
+    }
+
+    fn register_node(&mut self, id: &str) {
+        self.addrs.entry(id.to_string()).or_insert_with(|| id.to_string());
+    }
+}
+
+
+//
+// Async RPC, for `AsyncClient`.
+//
+// The same framing and messages as the blocking calls above, on tokio sockets.
+//
+
+async fn write_frame_async<M: Message>(stream: &mut tokio::net::TcpStream, msg: &M) -> std::io::Result<()> {
+    let body = msg.write_to_bytes()?;
+    stream.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await
+}
+
+async fn read_frame_async<M: Message>(stream: &mut tokio::net::TcpStream) -> std::io::Result<M> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_BYTES {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut body = vec![0u8; len as usize];
+    stream.read_exact(&mut body).await?;
+    Ok(M::parse_from_bytes(&body)?)
+}
+
+/// Make a call, failing if it takes longer than `timeout`. See `call`.
+async fn call_async<Req: Message, Res: Message>(addr: &str, req: &Req, timeout: Duration) -> std::io::Result<Res> {
+    let mut stream = tokio::time::timeout(RPC_TIMEOUT, tokio::net::TcpStream::connect(addr)).await.map_err(|_| timed_out())??;
+    stream.set_nodelay(true)?;
+    tokio::time::timeout(timeout, async {
+        write_frame_async(&mut stream, req).await?;
+        read_frame_async(&mut stream).await
+    }).await.map_err(|_| timed_out())?
+}
+
+/// An async stub for a master in another process. See `RemoteMaster`.
+#[derive(Debug, Clone)]
+pub(crate) struct AsyncRemoteMaster {
+    addr: String,
+}
+
+impl AsyncRemoteMaster {
+    pub(crate) fn new(addr: &str) -> AsyncRemoteMaster {
+        AsyncRemoteMaster { addr: addr.to_string() }
+    }
+
+    async fn run<T>(&self, call: MasterCall<T>) -> Result<T, MasterError> {
+        let res = call_async(&self.addr, &master_request(call.req), MASTER_RPC_TIMEOUT).await;
+        (call.parse)(master_response(&self.addr, res)?)
+    }
+
+    pub(crate) async fn append_file(&self, op: &AppendOperation) -> Result<(), MasterError> {
+        self.run(append_file_call(op)).await
+    }
+
+    pub(crate) async fn get_free_chunkservers(&self, num_chunks: u64, replication_factor: u8) -> Result<Vec<String>, MasterError> {
+        self.run(get_free_chunkservers_call(num_chunks, replication_factor)).await
+    }
+
+    pub(crate) async fn get_read_infos(&self, path: &str, offset: u64, length: u64) -> Result<ReadOperationInfo, MasterError> {
+        self.run(get_read_infos_call(path, offset, length)).await
+    }
+
+    pub(crate) async fn stat(&self, path: &str) -> Result<StatInfo, MasterError> {
+        self.run(stat_call(path)).await
+    }
+
+    pub(crate) async fn ls(&self, path: &str) -> Result<Vec<String>, MasterError> {
+        self.run(ls_call(path)).await
+    }
+}
+
+/// An async stub for a chunkserver in another process. See `RemoteChunkserver`.
+#[derive(Debug, Clone)]
+pub(crate) struct AsyncRemoteChunkserver {
+    addr: String,
+}
+
+impl AsyncRemoteChunkserver {
+    pub(crate) fn new(addr: &str) -> AsyncRemoteChunkserver {
+        AsyncRemoteChunkserver { addr: addr.to_string() }
+    }
+
+    async fn call(&self, req: ChunkserverRequest) -> Result<Vec<u8>, ChunkserverError> {
+        chunkserver_result(&self.addr, call_async(&self.addr, &chunkserver_request(req), RPC_TIMEOUT).await)
+    }
+
+    pub(crate) async fn push_chunk(&self, data: &[u8]) -> Result<(), ChunkserverError> {
+        self.call(push_chunk_request(data)).await.map(|_| ())
+    }
+
+    pub(crate) async fn read_chunk(&self, chunk_id: u64) -> Result<Vec<u8>, ChunkserverError> {
+        self.call(read_chunk_request(chunk_id)).await
+    }
+}
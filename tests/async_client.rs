@@ -0,0 +1,66 @@
+mod common;
+
+use gfs::async_client::AsyncClient;
+use gfs::chunk::CHUNK_SIZE_BYTES;
+use gfs::client::ClientError;
+use gfs::master::MasterError;
+use common::{free_addr, Cluster, Network};
+
+// The async client only speaks TCP.
+
+fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+    tokio::runtime::Runtime::new().unwrap().block_on(future)
+}
+
+fn async_client(cluster: &Cluster) -> AsyncClient {
+    AsyncClient::new(cluster.master_addr.as_ref().unwrap())
+}
+
+#[test]
+fn append_and_read_in_window() {
+    let cluster = Cluster::start("async-window", Network::Tcp);
+    let mut client = async_client(&cluster);
+    client.set_push_window(2);
+    // More chunks than are pushed at once.
+    let data: Vec<u8> = (0..CHUNK_SIZE_BYTES * 25 + 7).map(|i| (i + i / CHUNK_SIZE_BYTES) as u8).collect();
+
+    block_on(async {
+        client.append("/f", &data).await.unwrap();
+        assert_eq!(client.stat("/f").await.unwrap().length, data.len() as u64);
+        assert_eq!(client.ls("/").await.unwrap(), vec!["/f"]);
+        assert_eq!(client.read("/f", 0, u64::MAX).await.unwrap(), data);
+        assert_eq!(client.read("/f", 1000, 2000).await.unwrap(), data[1000..3000]);
+    });
+    assert_eq!(cluster.client().read_full("/f", cluster.network.clone()).unwrap(), data);
+}
+
+#[test]
+fn append_repeated_chunks() {
+    let cluster = Cluster::start("async-repeated", Network::Tcp);
+    let client = async_client(&cluster);
+    let data = [vec![0; CHUNK_SIZE_BYTES * 3], vec![1; 10], vec![0; CHUNK_SIZE_BYTES]].concat();
+
+    block_on(async {
+        client.append("/f", &data).await.unwrap();
+        assert_eq!(client.read("/f", 0, u64::MAX).await.unwrap(), data);
+    });
+}
+
+#[test]
+fn append_failure_is_returned() {
+    let cluster = Cluster::start("async-append-failure", Network::Tcp);
+    cluster.client().mkdir("/dir").unwrap();
+    let client = async_client(&cluster);
+
+    let res = block_on(client.append("/dir", b"data"));
+    assert!(matches!(res, Err(ClientError::Master(MasterError::InvalidPath))));
+    let res = cluster.client().append("/dir", b"data", cluster.network.clone());
+    assert!(matches!(res, Err(ClientError::Master(MasterError::InvalidPath))));
+}
+
+#[test]
+fn unreachable_master_is_unavailable() {
+    let client = AsyncClient::new(&free_addr());
+    assert!(matches!(block_on(client.stat("/f")), Err(MasterError::Unavailable)));
+    assert!(matches!(block_on(client.append("/f", b"data")), Err(ClientError::Master(MasterError::Unavailable))));
+}
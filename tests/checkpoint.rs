@@ -0,0 +1,87 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use gfs::common::NetworkShim;
+use gfs::master::{write_checkpoint, MasterServer};
+
+/// An empty directory for a test's master state.
+fn state_dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("checkpoint").join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn open_master(dir: &Path) -> MasterServer {
+    MasterServer::open(Arc::new(Mutex::new(NetworkShim::new())), dir.to_path_buf()).unwrap()
+}
+
+#[test]
+fn loads_json_state() {
+    let dir = state_dir("json");
+    let state = r#"{"file_table":{"/dir/file":{"length":1500,"chunks":[0,1]}},"chunk_counter":2}"#;
+    std::fs::write(dir.join("state"), state).unwrap();
+    let master = open_master(&dir);
+    assert_eq!(master.ls_tree("/"), vec!["/", "/dir/", "/dir/file"]);
+    assert_eq!(master.stat("/dir/file").unwrap().length, 1500);
+}
+
+#[test]
+fn loads_json_state_with_unnormalised_paths() {
+    let dir = state_dir("json-unnormalised");
+    let state = r#"{"file_table":{"test":{"length":10,"chunks":[0]},"dir//file/":{"length":20,"chunks":[1]}},"chunk_counter":2}"#;
+    std::fs::write(dir.join("state"), state).unwrap();
+    let mut master = open_master(&dir);
+    assert_eq!(master.ls_tree("/"), vec!["/", "/dir/", "/dir/file", "/test"]);
+    assert_eq!(master.stat("/test").unwrap().length, 10);
+    assert_eq!(master.stat("/dir/file").unwrap().length, 20);
+
+    master.rename("/test", "/x", false).unwrap();
+    drop(master);
+    let master = open_master(&dir);
+    assert_eq!(master.stat("/x").unwrap().length, 10);
+}
+
+#[test]
+fn rejects_json_state_with_conflicting_paths() {
+    let open = |dir: &Path| MasterServer::open(Arc::new(Mutex::new(NetworkShim::new())), dir.to_path_buf());
+    let states = [
+        r#"{"file_table":{"/a":{"length":0,"chunks":[]},"/a/b":{"length":0,"chunks":[]}},"chunk_counter":0}"#,
+        r#"{"file_table":{"/a":{"length":0,"chunks":[]},"a/":{"length":0,"chunks":[]}},"chunk_counter":0}"#,
+    ];
+    for (i, state) in states.iter().enumerate() {
+        let dir = state_dir(&format!("json-conflicting-{i}"));
+        std::fs::write(dir.join("state"), state).unwrap();
+        assert_eq!(open(&dir).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn checkpoint_round_trips() {
+    let dir = state_dir("current");
+    let mut master = open_master(&dir);
+    master.mkdir("/a/b").unwrap();
+    master.mkdir("/c").unwrap();
+    let (dir, sealed) = master.begin_checkpoint().unwrap();
+    write_checkpoint(&dir, sealed).unwrap();
+    drop(master);
+
+    assert!(std::fs::read(dir.join("checkpoint")).unwrap().starts_with(b"GFSCKPT1"));
+    let master = open_master(&dir);
+    assert_eq!(master.ls_tree("/"), vec!["/", "/a/", "/a/b/", "/c/"]);
+}
+
+#[test]
+fn unreadable_state_is_an_error() {
+    let dir = state_dir("unreadable");
+    let open = |dir: &Path| MasterServer::open(Arc::new(Mutex::new(NetworkShim::new())), dir.to_path_buf());
+
+    std::fs::write(dir.join("checkpoint"), b"NOTACKPT").unwrap();
+    assert_eq!(open(&dir).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+
+    std::fs::write(dir.join("checkpoint"), b"GFSCKPT1\xff\xff\xff").unwrap();
+    assert_eq!(open(&dir).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+
+    let dir = state_dir("unreadable-json");
+    std::fs::write(dir.join("state"), r#"{"file_table":{"#).unwrap();
+    assert_eq!(open(&dir).err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+}
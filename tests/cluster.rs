@@ -0,0 +1,469 @@
+mod common;
+
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use gfs::chunk::{ChunkHash, CHUNK_SIZE_BYTES};
+use gfs::chunkserver::ChunkserverError;
+use gfs::client::{ClientError, COMMIT_BATCH_CHUNKS};
+use gfs::master::{AppendOperation, MasterError, REPLICATION_FACTOR};
+use gfs::client::Client;
+use gfs::common::{sha256sum, ChunkserverHandle, Transport};
+use gfs::rpc::{RemoteChunkserver, RemoteMaster, TcpTransport, RPC_TIMEOUT};
+use common::{free_addr, Cluster, Network};
+
+/// Data which differs at every offset, so misplaced reads are caught.
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed ^ (i / 251) as u8)).collect()
+}
+
+fn append_and_read(network: Network) {
+    let cluster = Cluster::start("append-and-read", network);
+    let client = cluster.client();
+    let first = pattern(CHUNK_SIZE_BYTES * 2 + 100, 1);
+    let second = pattern(10, 2);
+    client.append("/f", &first, cluster.network.clone()).unwrap();
+    client.append("/f", &second, cluster.network.clone()).unwrap();
+
+    assert_eq!(client.read_full("/f", cluster.network.clone()).unwrap(), [first.clone(), second].concat());
+    assert_eq!(client.read("/f", 1000, 100, cluster.network.clone()).unwrap(), first[1000..1100]);
+}
+
+fn append_repeated_chunks(network: Network) {
+    let cluster = Cluster::start("append-repeated", network);
+    let client = cluster.client();
+    let zeros = vec![0; CHUNK_SIZE_BYTES * 2];
+    client.append("/zeros", &zeros, cluster.network.clone()).unwrap();
+    assert_eq!(client.read_full("/zeros", cluster.network.clone()).unwrap(), zeros);
+
+    let data = [pattern(CHUNK_SIZE_BYTES, 5), pattern(CHUNK_SIZE_BYTES, 6), pattern(CHUNK_SIZE_BYTES, 5), vec![0; 10]].concat();
+    client.append("/mixed", &data, cluster.network.clone()).unwrap();
+    assert_eq!(client.read_full("/mixed", cluster.network.clone()).unwrap(), data);
+}
+
+fn large_appends_are_atomic(network: Network) {
+    let cluster = Cluster::start("append-large", network);
+    let client = Arc::new(cluster.client());
+    let records: Vec<Vec<u8>> = (0..2).map(|seed| pattern(CHUNK_SIZE_BYTES * 60 + 1, seed)).collect();
+
+    // Appended at once, the records land one after the other, never interleaved.
+    std::thread::scope(|scope| {
+        for record in records.iter() {
+            let (client, network) = (client.clone(), cluster.network.clone());
+            scope.spawn(move || client.append("/f", record, network).unwrap());
+        }
+    });
+    let data = client.read_full("/f", cluster.network.clone()).unwrap();
+    assert!(data == [records[0].clone(), records[1].clone()].concat() || data == [records[1].clone(), records[0].clone()].concat(), "appends were interleaved");
+}
+
+fn rename_semantics(network: Network) {
+    let cluster = Cluster::start("rename", network);
+    let client = cluster.client();
+    client.append("/a", b"a", cluster.network.clone()).unwrap();
+    client.append("/b", b"b", cluster.network.clone()).unwrap();
+    client.mkdir("/d/e").unwrap();
+    client.mkdir("/full").unwrap();
+    client.append("/full/x", b"x", cluster.network.clone()).unwrap();
+    client.mkdir("/empty").unwrap();
+
+    // Files are only replaced when asked.
+    assert!(matches!(client.rename("/a", "/b", false), Err(MasterError::FileExists)));
+    client.rename("/a", "/b", true).unwrap();
+    assert_eq!(client.read_full("/b", cluster.network.clone()).unwrap(), b"a");
+    assert!(matches!(cluster.master_handle.stat("/a"), Err(MasterError::FileNotFound)));
+    assert!(matches!(client.read_full("/a", cluster.network.clone()), Err(ClientError::Master(MasterError::FileNotFound))));
+
+    // A directory can't be moved into itself, or replace a non-empty directory.
+    assert!(matches!(client.rename("/d", "/d/e/f", false), Err(MasterError::InvalidPath)));
+    assert!(matches!(client.rename("/d/e", "/d", true), Err(MasterError::InvalidPath)));
+    assert!(matches!(client.rename("/d", "/full", true), Err(MasterError::DirectoryNotEmpty)));
+    assert_eq!(client.read_full("/full/x", cluster.network.clone()).unwrap(), b"x");
+
+    // Files and directories don't replace each other.
+    assert!(matches!(client.rename("/b", "/empty", true), Err(MasterError::InvalidPath)));
+    assert!(matches!(client.rename("/empty", "/b", true), Err(MasterError::InvalidPath)));
+
+    // A directory moves with everything in it, and can replace an empty directory.
+    client.rename("/d", "/empty", true).unwrap();
+    assert_eq!(client.ls_tree("/").unwrap(), vec!["/", "/b", "/empty/", "/empty/e/", "/full/", "/full/x"]);
+}
+
+fn snapshot_semantics(network: Network) {
+    let cluster = Cluster::start("snapshot", network);
+    let client = cluster.client();
+    client.append("/dir/f", b"one", cluster.network.clone()).unwrap();
+    client.snapshot("/dir", "/copy").unwrap();
+    client.snapshot("/dir/f", "/g").unwrap();
+    assert!(matches!(client.snapshot("/dir/f", "/g"), Err(MasterError::FileExists)));
+    // Each snapshot is granted as a mutation of the chunks, bumping their versions.
+    for (id, chunk_id) in replicas(&cluster, "/dir/f") {
+        assert!(cluster.replica_path(&id, chunk_id).ends_with(format!("ch{chunk_id}.3")));
+    }
+
+    // The copies share chunks, but appends to one don't show up in the others.
+    client.append("/dir/f", b"two", cluster.network.clone()).unwrap();
+    client.append("/g", b"three", cluster.network.clone()).unwrap();
+    assert_eq!(client.read_full("/dir/f", cluster.network.clone()).unwrap(), b"onetwo");
+    assert_eq!(client.read_full("/copy/f", cluster.network.clone()).unwrap(), b"one");
+    assert_eq!(client.read_full("/g", cluster.network.clone()).unwrap(), b"onethree");
+
+    // Deleting the original leaves the copies readable.
+    client.delete("/dir/f").unwrap();
+    assert_eq!(client.read_full("/copy/f", cluster.network.clone()).unwrap(), b"one");
+}
+
+fn hidden_names_are_reserved(network: Network) {
+    let cluster = Cluster::start("reserved", network);
+    let client = cluster.client();
+    client.append("/f", b"f", cluster.network.clone()).unwrap();
+    let hidden = "/d/.deleted.1.f";
+
+    assert!(matches!(client.mkdir(hidden), Err(MasterError::InvalidPath)));
+    assert!(matches!(client.rename("/f", hidden, false), Err(MasterError::InvalidPath)));
+    assert!(matches!(client.snapshot("/f", hidden), Err(MasterError::InvalidPath)));
+    assert!(matches!(client.append(hidden, b"f", cluster.network.clone()), Err(ClientError::Master(MasterError::InvalidPath))));
+    assert_eq!(client.ls_tree("/").unwrap(), vec!["/", "/f"]);
+
+    // Deleted files can still be restored.
+    client.delete("/f").unwrap();
+    assert_eq!(client.ls_tree("/").unwrap(), vec!["/"]);
+    client.undelete("/f").unwrap();
+    assert_eq!(client.read_full("/f", cluster.network.clone()).unwrap(), b"f");
+}
+
+fn file_reads_across_chunk_boundaries(network: Network) {
+    let cluster = Cluster::start("file-seek", network);
+    let client = cluster.client();
+    let data = pattern(CHUNK_SIZE_BYTES * 3 - 50, 3);
+    client.append("/f", &data, cluster.network.clone()).unwrap();
+
+    let mut file = client.open("/f", cluster.network.clone()).unwrap();
+    assert_eq!(file.len(), data.len() as u64);
+    let boundary = CHUNK_SIZE_BYTES as u64;
+    let mut buf = vec![0; 16];
+
+    file.seek(SeekFrom::Start(boundary - 8)).unwrap();
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data[CHUNK_SIZE_BYTES - 8..CHUNK_SIZE_BYTES + 8]);
+
+    file.seek(SeekFrom::Start(boundary)).unwrap();
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data[CHUNK_SIZE_BYTES..CHUNK_SIZE_BYTES + 16]);
+
+    file.seek(SeekFrom::Current(-17)).unwrap();
+    file.read_exact(&mut buf[..1]).unwrap();
+    assert_eq!(buf[0], data[CHUNK_SIZE_BYTES - 1]);
+
+    file.seek(SeekFrom::End(-5)).unwrap();
+    let mut rest = vec![];
+    file.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, data[data.len() - 5..]);
+    assert!(file.seek(SeekFrom::Current(-(data.len() as i64) - 1)).is_err());
+
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut all = vec![];
+    file.read_to_end(&mut all).unwrap();
+    assert_eq!(all, data);
+}
+
+fn file_tails_appends(network: Network) {
+    let cluster = Cluster::start("file-tail", network);
+    let client = cluster.client();
+    client.append("/log", b"first\n", cluster.network.clone()).unwrap();
+
+    let mut file = client.open("/log", cluster.network.clone()).unwrap();
+    let mut buf = vec![];
+    file.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"first\n");
+    assert_eq!(file.read(&mut [0; 8]).unwrap(), 0);
+
+    client.append("/log", b"second\n", cluster.network.clone()).unwrap();
+    buf.clear();
+    file.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"second\n");
+    assert_eq!(file.len(), 13);
+}
+
+fn reads_fall_back_from_corrupt_replica(network: Network) {
+    let cluster = Cluster::start("corrupt-replica", network);
+    let client = cluster.client();
+    let data = pattern(CHUNK_SIZE_BYTES, 4);
+    client.append("/f", &data, cluster.network.clone()).unwrap();
+
+    let read_info = cluster.master_handle.get_read_infos("/f", 0, u64::MAX).unwrap();
+    let chunk_read = &read_info.chunk_reads[0];
+    let path = cluster.replica_path(&chunk_read.locations[0], chunk_read.chunk_id);
+    let mut replica = std::fs::read(&path).unwrap();
+    replica[100] ^= 0xff;
+    std::fs::write(&path, replica).unwrap();
+
+    assert_eq!(client.read_full("/f", cluster.network.clone()).unwrap(), data);
+}
+
+/// A chunkserver which returns its chunks cut short.
+struct ShortReads(Arc<dyn ChunkserverHandle>);
+
+impl ChunkserverHandle for ShortReads {
+    fn push_chunk(&self, data: &[u8]) -> Result<(), ChunkserverError> {
+        self.0.push_chunk(data)
+    }
+
+    fn commit_chunk(&self, chunk_hash: ChunkHash, chunk_id: u64, version: u64) -> Result<(), ChunkserverError> {
+        self.0.commit_chunk(chunk_hash, chunk_id, version)
+    }
+
+    fn read_chunk(&self, chunk_id: u64) -> Result<Vec<u8>, ChunkserverError> {
+        self.0.read_chunk(chunk_id).map(|data| data[..10].to_vec())
+    }
+
+    fn replicate_chunk(&self, chunk_id: u64, version: u64, source: &str) -> Result<(), ChunkserverError> {
+        self.0.replicate_chunk(chunk_id, version, source)
+    }
+
+    fn update_chunk_version(&self, chunk_id: u64, version: u64) -> Result<(), ChunkserverError> {
+        self.0.update_chunk_version(chunk_id, version)
+    }
+}
+
+/// A transport through which one chunkserver's reads are cut short.
+struct ShortReadsFrom {
+    inner: Arc<Mutex<dyn Transport>>,
+    id: String,
+}
+
+impl Transport for ShortReadsFrom {
+    fn get_node(&self, id: &str) -> Option<Arc<dyn ChunkserverHandle>> {
+        let node = self.inner.lock().unwrap().get_node(id)?;
+        Some(if id == self.id { Arc::new(ShortReads(node)) } else { node })
+    }
+}
+
+fn reads_fall_back_from_short_replica(network: Network) {
+    let cluster = Cluster::start("short-replica", network);
+    let client = cluster.client();
+    let data = pattern(CHUNK_SIZE_BYTES * 2, 7);
+    client.append("/f", &data, cluster.network.clone()).unwrap();
+
+    let (id, _) = replicas(&cluster, "/f")[0].clone();
+    let network: Arc<Mutex<dyn Transport>> = Arc::new(Mutex::new(ShortReadsFrom { inner: cluster.network.clone(), id }));
+    assert_eq!(client.read_full("/f", network.clone()).unwrap(), data);
+    let mut read = vec![];
+    client.open("/f", network).unwrap().read_to_end(&mut read).unwrap();
+    assert_eq!(read, data);
+}
+
+fn appends_to_unregistered_chunkservers_are_rejected(network: Network) {
+    let cluster = Cluster::start("unregistered", network);
+    let data = b"data";
+    // Nothing was pushed, but the master must not even try to reach a chunkserver it doesn't know.
+    let op = AppendOperation {
+        file_path: "/f".to_string(),
+        length: data.len() as u64,
+        chunk_sequence: vec![sha256sum(data)],
+        chunk_locations: HashMap::from([(sha256sum(data), vec![free_addr()])]),
+    };
+    assert!(matches!(cluster.master_handle.append_file(op), Err(MasterError::ChunkserverNotFound)));
+    assert!(matches!(cluster.master_handle.stat("/f"), Err(MasterError::FileNotFound)));
+}
+
+fn writer_commits_across_flushes(network: Network) {
+    let cluster = Cluster::start("writer-flushes", network);
+    let client = cluster.client();
+    let first = pattern(CHUNK_SIZE_BYTES * COMMIT_BATCH_CHUNKS + 100, 1);
+    let second = pattern(CHUNK_SIZE_BYTES * 2 + 50, 2);
+    let mut writer = client.append_writer("/f", cluster.network.clone());
+    // Written in pieces which don't line up with chunks.
+    first.chunks(700).for_each(|piece| writer.write_all(piece).unwrap());
+    writer.flush().unwrap();
+    assert_eq!(client.read_full("/f", cluster.network.clone()).unwrap(), first);
+
+    second.chunks(700).for_each(|piece| writer.write_all(piece).unwrap());
+    writer.close().unwrap();
+    assert_eq!(client.read_full("/f", cluster.network.clone()).unwrap(), [first, second].concat());
+}
+
+fn writer_repeated_chunks(network: Network) {
+    let cluster = Cluster::start("writer-repeated", network);
+    let client = cluster.client();
+    let data = [vec![0; CHUNK_SIZE_BYTES * 3], pattern(CHUNK_SIZE_BYTES, 5), vec![0; CHUNK_SIZE_BYTES + 10]].concat();
+    let mut writer = client.append_writer("/zeros", cluster.network.clone());
+    writer.write_all(&data).unwrap();
+    writer.close().unwrap();
+    assert_eq!(client.read_full("/zeros", cluster.network.clone()).unwrap(), data);
+}
+
+fn writer_flushes_on_drop(network: Network) {
+    let cluster = Cluster::start("writer-drop", network);
+    let client = cluster.client();
+    let data = pattern(CHUNK_SIZE_BYTES + 100, 3);
+    let mut writer = client.append_writer("/f", cluster.network.clone());
+    writer.write_all(&data).unwrap();
+    assert!(cluster.master_handle.stat("/f").is_err());
+    drop(writer);
+    assert_eq!(client.read_full("/f", cluster.network.clone()).unwrap(), data);
+}
+
+fn writer_keeps_data_until_committed(network: Network) {
+    let cluster = Cluster::start("writer-errors", network);
+    let client = cluster.client();
+    client.mkdir("/d").unwrap();
+    let data = pattern(CHUNK_SIZE_BYTES * 2, 6);
+    let mut writer = client.append_writer("/d", cluster.network.clone());
+    writer.write_all(&data).unwrap();
+
+    // The last chunk is pushed by the next write, which takes nothing if the push fails.
+    cluster.chunkservers.iter().for_each(|(id, _)| cluster.kill(id));
+    assert!(writer.write(b"tail").is_err());
+    cluster.chunkservers.iter().for_each(|(id, _)| cluster.revive(id));
+    writer.write_all(b"tail").unwrap();
+
+    // The path is a directory, so the commit fails, but the batch is kept for the next one.
+    assert!(writer.flush().is_err());
+    client.rmdir("/d").unwrap();
+    writer.close().unwrap();
+    assert_eq!(client.read_full("/d", cluster.network.clone()).unwrap(), [data, b"tail".to_vec()].concat());
+}
+
+/// The replicas of a file's chunks, as chunkserver ID and chunk ID.
+fn replicas(cluster: &Cluster, path: &str) -> Vec<(String, u64)> {
+    let read_info = cluster.master_handle.get_read_infos(path, 0, u64::MAX).unwrap();
+    read_info.chunk_reads.iter()
+        .flat_map(|chunk_read| chunk_read.locations.iter().map(|location| (location.clone(), chunk_read.chunk_id)))
+        .collect()
+}
+
+fn gc_reclaims_deleted_files_after_grace_period(network: Network) {
+    let cluster = Cluster::start("gc-grace", network);
+    let client = cluster.client();
+    client.append("/f", b"data", cluster.network.clone()).unwrap();
+    let replicas = replicas(&cluster, "/f");
+    cluster.master.lock().unwrap().set_deletion_grace_period(Duration::from_secs(3600));
+    client.delete("/f").unwrap();
+
+    // Within the grace period, the file is kept, and can be restored.
+    cluster.master.lock().unwrap().collect_garbage();
+    client.undelete("/f").unwrap();
+    assert_eq!(client.read_full("/f", cluster.network.clone()).unwrap(), b"data");
+
+    client.delete("/f").unwrap();
+    cluster.master.lock().unwrap().set_deletion_grace_period(Duration::ZERO);
+    std::thread::sleep(Duration::from_millis(2));
+    cluster.master.lock().unwrap().collect_garbage();
+    assert!(matches!(client.undelete("/f"), Err(MasterError::FileNotFound)));
+    assert_eq!(client.ls_tree("/").unwrap(), vec!["/"]);
+    cluster.wait_until("the replicas are deleted", Duration::from_secs(10), || {
+        replicas.iter().all(|(id, chunk_id)| cluster.find_replica(id, *chunk_id).is_none())
+    });
+}
+
+fn gc_keeps_chunks_referenced_by_snapshot(network: Network) {
+    let cluster = Cluster::start("gc-snapshot", network);
+    let client = cluster.client();
+    client.append("/f", b"shared", cluster.network.clone()).unwrap();
+    client.append("/g", b"unshared", cluster.network.clone()).unwrap();
+    client.snapshot("/f", "/copy").unwrap();
+    let (shared, unshared) = (replicas(&cluster, "/f"), replicas(&cluster, "/g"));
+    cluster.master.lock().unwrap().set_deletion_grace_period(Duration::ZERO);
+    client.delete("/f").unwrap();
+    client.delete("/g").unwrap();
+    std::thread::sleep(Duration::from_millis(2));
+    cluster.master.lock().unwrap().collect_garbage();
+
+    // Once the unshared chunk is deleted, the chunkservers have been told what to delete.
+    cluster.wait_until("the unshared replicas are deleted", Duration::from_secs(10), || {
+        unshared.iter().all(|(id, chunk_id)| cluster.find_replica(id, *chunk_id).is_none())
+    });
+    assert!(shared.iter().all(|(id, chunk_id)| cluster.find_replica(id, *chunk_id).is_some()));
+    assert_eq!(replicas(&cluster, "/copy"), shared);
+    assert_eq!(client.read_full("/copy", cluster.network.clone()).unwrap(), b"shared");
+}
+
+fn gc_deletes_orphaned_replicas(network: Network) {
+    let cluster = Cluster::start("gc-orphans", network);
+    let client = cluster.client();
+    client.append("/d/f", b"data", cluster.network.clone()).unwrap();
+    let replicas = replicas(&cluster, "/d/f");
+    client.delete("/d/f").unwrap();
+    // Removing the directory reclaims the deleted file straight away, orphaning its chunks.
+    client.rmdir("/d").unwrap();
+    assert!(replicas.iter().all(|(id, chunk_id)| cluster.find_replica(id, *chunk_id).is_some()));
+
+    cluster.master.lock().unwrap().collect_garbage();
+    cluster.wait_until("the orphaned replicas are deleted", Duration::from_secs(10), || {
+        replicas.iter().all(|(id, chunk_id)| cluster.find_replica(id, *chunk_id).is_none())
+    });
+}
+
+fn lost_replicas_are_restored(network: Network) {
+    let cluster = Cluster::start_with("re-replication", network, REPLICATION_FACTOR + 1);
+    let client = cluster.client();
+    let data = pattern(CHUNK_SIZE_BYTES * 3, 8);
+    client.append("/f", &data, cluster.network.clone()).unwrap();
+    let (killed, _) = replicas(&cluster, "/f")[0].clone();
+    let lost: Vec<u64> = replicas(&cluster, "/f").into_iter().filter(|(id, _)| *id == killed).map(|(_, chunk_id)| chunk_id).collect();
+    cluster.kill(&killed);
+
+    // Once the chunkserver is found dead, its replicas are copied to the spare one.
+    cluster.wait_until("every chunk is fully replicated", Duration::from_secs(20), || {
+        let read_info = cluster.master_handle.get_read_infos("/f", 0, u64::MAX).unwrap();
+        read_info.chunk_reads.iter().all(|chunk_read| {
+            chunk_read.locations.len() == REPLICATION_FACTOR && !chunk_read.locations.contains(&killed)
+        })
+    });
+    // Copying a chunk is granted as a mutation, so the live replicas of the lost chunks are at a new version.
+    let live = replicas(&cluster, "/f");
+    for (id, chunk_id) in live.iter().filter(|(_, chunk_id)| lost.contains(chunk_id)) {
+        assert!(cluster.replica_path(id, *chunk_id).ends_with(format!("ch{chunk_id}.2")));
+    }
+
+    // The revived chunkserver's replicas missed that, so they are stale, and are deleted.
+    cluster.revive(&killed);
+    cluster.wait_until("the stale replicas are deleted", Duration::from_secs(10), || {
+        lost.iter().all(|chunk_id| cluster.find_replica(&killed, *chunk_id).is_none())
+    });
+    assert_eq!(replicas(&cluster, "/f"), live);
+    assert_eq!(client.read_full("/f", cluster.network.clone()).unwrap(), data);
+}
+
+cluster_tests!(
+    append_and_read,
+    append_repeated_chunks,
+    large_appends_are_atomic,
+    rename_semantics,
+    snapshot_semantics,
+    hidden_names_are_reserved,
+    file_reads_across_chunk_boundaries,
+    file_tails_appends,
+    reads_fall_back_from_corrupt_replica,
+    reads_fall_back_from_short_replica,
+    appends_to_unregistered_chunkservers_are_rejected,
+    writer_commits_across_flushes,
+    writer_repeated_chunks,
+    writer_flushes_on_drop,
+    writer_keeps_data_until_committed,
+    gc_reclaims_deleted_files_after_grace_period,
+    gc_keeps_chunks_referenced_by_snapshot,
+    gc_deletes_orphaned_replicas,
+    lost_replicas_are_restored,
+);
+
+#[test]
+fn unreachable_master_is_unavailable() {
+    // Nothing listens on the address, as the port was only bound to pick it.
+    let client = Client::new(Arc::new(RemoteMaster::new(&free_addr())));
+    assert!(matches!(client.ls("/"), Err(MasterError::Unavailable)));
+    assert!(matches!(client.df(), Err(MasterError::Unavailable)));
+    assert!(matches!(client.append("/f", b"data", Arc::new(Mutex::new(TcpTransport::new()))), Err(ClientError::Master(MasterError::Unavailable))));
+}
+
+#[test]
+fn hung_chunkserver_is_unavailable() {
+    // The connection is accepted by the kernel, but nothing ever answers.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let chunkserver = RemoteChunkserver::new(&listener.local_addr().unwrap().to_string());
+    let start = std::time::Instant::now();
+    assert!(matches!(chunkserver.read_chunk(0), Err(ChunkserverError::Unavailable)));
+    assert!(start.elapsed() < RPC_TIMEOUT * 2);
+}
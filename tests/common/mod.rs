@@ -0,0 +1,255 @@
+//! A cluster of a master and chunkservers in this process, for tests.
+
+// Each test crate uses a different part of this.
+#![allow(dead_code)]
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use gfs::chunkserver::{Chunkserver, ChunkserverStorage};
+use gfs::client::Client;
+use gfs::common::{ChunkserverHandle, MasterHandle, NetworkShim, Transport};
+use gfs::master::{*};
+use gfs::rpc::{serve_chunkserver, serve_master, RemoteMaster, TcpTransport};
+
+/// How the nodes of a cluster reach each other.
+#[derive(Debug, Clone, Copy)]
+pub enum Network {
+    /// In memory, through a `NetworkShim`.
+    Shim,
+    /// Over localhost sockets, through a `TcpTransport`.
+    Tcp,
+}
+
+/// The chunkservers a test has killed.
+type Killed = Arc<Mutex<HashSet<String>>>;
+
+/// A transport which can't reach killed chunkservers.
+struct KillableTransport {
+    inner: Arc<Mutex<dyn Transport>>,
+    killed: Killed,
+}
+
+impl Transport for KillableTransport {
+    fn get_node(&self, id: &str) -> Option<Arc<dyn ChunkserverHandle>> {
+        if self.killed.lock().unwrap().contains(id) {
+            return None;
+        }
+        self.inner.lock().unwrap().get_node(id)
+    }
+
+    fn register_node(&mut self, id: &str) {
+        self.inner.lock().unwrap().register_node(id);
+    }
+}
+
+/// A chunkserver's link to the master, which drops its heartbeats once it is killed.
+struct ChunkserverLink {
+    id: String,
+    master: Arc<dyn MasterHandle>,
+    killed: Killed,
+}
+
+impl MasterHandle for ChunkserverLink {
+    fn receive_heartbeat(&self, heartbeat: Heartbeat) -> HeartbeatResponse {
+        if self.killed.lock().unwrap().contains(&self.id) {
+            return HeartbeatResponse { full_report_needed: false, delete_chunks: HashMap::new() };
+        }
+        self.master.receive_heartbeat(heartbeat)
+    }
+
+    fn append_file(&self, op: AppendOperation) -> Result<(), MasterError> {
+        self.master.append_file(op)
+    }
+
+    fn get_free_chunkservers(&self, num_chunks: u64, replication_factor: u8) -> Result<Vec<String>, MasterError> {
+        self.master.get_free_chunkservers(num_chunks, replication_factor)
+    }
+
+    fn get_read_infos(&self, path: &str, offset: u64, length: u64) -> Result<ReadOperationInfo, MasterError> {
+        self.master.get_read_infos(path, offset, length)
+    }
+
+    fn stat(&self, path: &str) -> Result<StatInfo, MasterError> {
+        self.master.stat(path)
+    }
+
+    fn ls(&self, path: &str) -> Result<Vec<String>, MasterError> {
+        self.master.ls(path)
+    }
+
+    fn ls_tree(&self, path: &str) -> Result<Vec<String>, MasterError> {
+        self.master.ls_tree(path)
+    }
+
+    fn df(&self) -> Result<u64, MasterError> {
+        self.master.df()
+    }
+
+    fn du(&self) -> Result<u64, MasterError> {
+        self.master.du()
+    }
+
+    fn delete(&self, path: &str) -> Result<(), MasterError> {
+        self.master.delete(path)
+    }
+
+    fn undelete(&self, path: &str) -> Result<(), MasterError> {
+        self.master.undelete(path)
+    }
+
+    fn rename(&self, from: &str, to: &str, overwrite: bool) -> Result<(), MasterError> {
+        self.master.rename(from, to, overwrite)
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), MasterError> {
+        self.master.mkdir(path)
+    }
+
+    fn rmdir(&self, path: &str) -> Result<(), MasterError> {
+        self.master.rmdir(path)
+    }
+
+    fn snapshot(&self, from: &str, to: &str) -> Result<(), MasterError> {
+        self.master.snapshot(from, to)
+    }
+}
+
+pub struct Cluster {
+    /// The master itself, for tests to drive its background tasks.
+    pub master: Arc<Mutex<MasterServer>>,
+    /// The master, as clients and chunkservers reach it.
+    pub master_handle: Arc<dyn MasterHandle>,
+    /// The address the master listens on, over TCP.
+    pub master_addr: Option<String>,
+    pub network: Arc<Mutex<dyn Transport>>,
+    /// The ID and storage directory of each chunkserver.
+    pub chunkservers: Vec<(String, PathBuf)>,
+    killed: Killed,
+}
+
+/// A localhost address nothing is listening on.
+pub fn free_addr() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+impl Cluster {
+    /// Start a master and `REPLICATION_FACTOR` chunkservers, with their state in a fresh
+    /// directory, and wait for the chunkservers to register.
+    pub fn start(name: &str, network: Network) -> Cluster {
+        Cluster::start_with(name, network, REPLICATION_FACTOR)
+    }
+
+    /// Start a cluster, as `start`, of `n` chunkservers.
+    pub fn start_with(name: &str, network: Network, n: usize) -> Cluster {
+        let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("cluster").join(format!("{name}-{network:?}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("master")).unwrap();
+
+        let shim = Arc::new(Mutex::new(NetworkShim::new()));
+        let inner: Arc<Mutex<dyn Transport>> = match network {
+            Network::Shim => shim.clone(),
+            Network::Tcp => Arc::new(Mutex::new(TcpTransport::new())),
+        };
+        let killed = Killed::default();
+        let transport: Arc<Mutex<dyn Transport>> = Arc::new(Mutex::new(KillableTransport { inner, killed: killed.clone() }));
+
+        let master = Arc::new(Mutex::new(MasterServer::open(transport.clone(), dir.join("master")).unwrap()));
+        let master_thread = master.clone();
+        std::thread::spawn(move || MasterServer::run(master_thread));
+        let master_addr = match network {
+            Network::Shim => None,
+            Network::Tcp => Some(free_addr()),
+        };
+        let master_handle: Arc<dyn MasterHandle> = match &master_addr {
+            None => master.clone(),
+            Some(addr) => {
+                serve_master(master.clone(), addr).unwrap();
+                Arc::new(RemoteMaster::new(addr))
+            }
+        };
+
+        let mut chunkservers = vec![];
+        for i in 0..n {
+            // Over TCP, a chunkserver's ID is its listen address.
+            let id = match network {
+                Network::Shim => format!("chunkserver-{i}"),
+                Network::Tcp => free_addr(),
+            };
+            let storage_dir = dir.join(format!("chunkserver-{i}"));
+            let storage = Box::new(ChunkserverStorage::new(storage_dir.clone()));
+            let link = Arc::new(ChunkserverLink { id: id.clone(), master: master_handle.clone(), killed: killed.clone() });
+            let chunkserver = Arc::new(Mutex::new(Chunkserver::new(link, transport.clone(), id.clone(), 1 << 30, storage)));
+            match network {
+                Network::Shim => shim.lock().unwrap().add_node(chunkserver.clone()),
+                Network::Tcp => drop(serve_chunkserver(chunkserver.clone(), &id).unwrap()),
+            }
+            std::thread::spawn(move || Chunkserver::run(chunkserver));
+            chunkservers.push((id, storage_dir));
+        }
+
+        let start = Instant::now();
+        while master.lock().unwrap().get_free_chunkservers(1, REPLICATION_FACTOR as u8).len() < n {
+            assert!(start.elapsed() < Duration::from_secs(10), "chunkservers never registered");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        Cluster { master, master_handle, master_addr, network: transport, chunkservers, killed }
+    }
+
+    /// Cut a chunkserver off, as if it crashed: its heartbeats stop reaching the master,
+    /// and nothing can reach it.
+    pub fn kill(&self, chunkserver_id: &str) {
+        self.killed.lock().unwrap().insert(chunkserver_id.to_string());
+    }
+
+    /// Reconnect a killed chunkserver, as if it restarted with the replicas it held.
+    pub fn revive(&self, chunkserver_id: &str) {
+        self.killed.lock().unwrap().remove(chunkserver_id);
+    }
+
+    pub fn client(&self) -> Client {
+        Client::new(self.master_handle.clone())
+    }
+
+    /// The file holding a chunkserver's replica of a chunk.
+    pub fn replica_path(&self, chunkserver_id: &str, chunk_id: u64) -> PathBuf {
+        self.find_replica(chunkserver_id, chunk_id).unwrap()
+    }
+
+    /// The file holding a chunkserver's replica of a chunk, if it has one.
+    pub fn find_replica(&self, chunkserver_id: &str, chunk_id: u64) -> Option<PathBuf> {
+        let (_, dir) = self.chunkservers.iter().find(|(id, _)| id == chunkserver_id).unwrap();
+        std::fs::read_dir(dir).unwrap()
+            .map(|file| file.unwrap().path())
+            .find(|path| {
+                let name = path.file_name().unwrap().to_str().unwrap();
+                name.strip_prefix(&format!("ch{chunk_id}."))
+                    .is_some_and(|version| version.parse::<u64>().is_ok())
+            })
+    }
+
+    /// Wait until `done` holds, failing the test if it takes more than `timeout`.
+    pub fn wait_until(&self, what: &str, timeout: Duration, mut done: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < timeout, "timed out waiting until {what}");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Define a test for each network, running `$name(network)`.
+#[macro_export]
+macro_rules! cluster_tests {
+    ($($name:ident),* $(,)?) => {
+        mod shim {
+            $(#[test] fn $name() { super::$name($crate::common::Network::Shim) })*
+        }
+        mod tcp {
+            $(#[test] fn $name() { super::$name($crate::common::Network::Tcp) })*
+        }
+    };
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use gfs::chunk::CHUNK_SIZE_BYTES;
+use gfs::common::{MasterHandle, NetworkShim};
+use gfs::master::{Heartbeat, MasterError, MasterServer, HEARTBEAT_INTERVAL, MAX_MISSED_HEARTBEATS};
+
+fn open_master(dir: &Path) -> MasterServer {
+    MasterServer::open(Arc::new(Mutex::new(NetworkShim::new())), dir.to_path_buf()).unwrap()
+}
+
+/// A master whose state is `/f`, made of chunks 0 to `chunks - 1` at version 1, with no
+/// chunkservers. Returns the directory its state is in too.
+fn master_with_file(name: &str, chunks: u64) -> (MasterServer, PathBuf) {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("master").join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let chunk_ids: Vec<u64> = (0..chunks).collect();
+    let versions: Vec<String> = chunk_ids.iter().map(|chunk_id| format!(r#""{chunk_id}":1"#)).collect();
+    let state = format!(
+        r#"{{"file_table":{{"/f":{{"length":{},"chunks":{chunk_ids:?}}}}},"chunk_counter":{chunks},"chunk_versions":{{{}}}}}"#,
+        chunks * CHUNK_SIZE_BYTES as u64,
+        versions.join(","),
+    );
+    std::fs::write(dir.join("state"), state).unwrap();
+    (open_master(&dir), dir)
+}
+
+/// A full report from a chunkserver holding `chunks` at version 1, with `disk_free` bytes free.
+fn full_report(id: &str, chunks: &[u64], disk_free: u64) -> Heartbeat {
+    Heartbeat {
+        chunkserver_id: id.to_string(),
+        disk_used: 0,
+        disk_free,
+        full_report: true,
+        added_chunks: chunks.iter().map(|chunk_id| (*chunk_id, 1)).collect(),
+        removed_chunks: vec![],
+        corrupt_chunks: vec![],
+    }
+}
+
+#[test]
+fn replicates_chunks_missing_most_replicas_first() {
+    let (mut master, _) = master_with_file("replication-priority", 3);
+    // Nothing is replicated until chunkservers have had time to report.
+    std::thread::sleep(HEARTBEAT_INTERVAL * MAX_MISSED_HEARTBEATS);
+    master.receive_heartbeat(full_report("a", &[0, 1, 2], 1));
+    master.receive_heartbeat(full_report("b", &[0, 1], 2));
+    master.receive_heartbeat(full_report("c", &[1], 3));
+    master.receive_heartbeat(full_report("d", &[], 4));
+
+    // Chunk 2 is missing two replicas, chunk 0 one, and chunk 1 none.
+    let replications = master.next_replications(1);
+    assert_eq!(replications.len(), 1);
+    assert_eq!((replications[0].chunk_id, replications[0].source.as_str(), replications[0].destination.as_str()), (2, "a", "d"));
+    let replications = master.next_replications(10);
+    assert_eq!(replications.iter().map(|x| x.chunk_id).collect::<Vec<_>>(), vec![0]);
+    assert_eq!(replications[0].destination, "d");
+    assert!(master.next_replications(10).is_empty());
+}
+
+/// A heartbeat from a chunkserver with nothing to report.
+fn heartbeat(id: &str) -> Heartbeat {
+    Heartbeat { full_report: false, ..full_report(id, &[], 1) }
+}
+
+/// The chunkservers a read of `/f` would be sent to, for each chunk.
+fn read_locations(master: &MasterServer) -> Vec<Vec<String>> {
+    let read_info = master.get_read_infos("/f", 0, u64::MAX).unwrap();
+    read_info.chunk_reads.into_iter().map(|chunk_read| chunk_read.locations).collect()
+}
+
+#[test]
+fn expired_chunkservers_are_dropped_from_locations() {
+    let (mut master, _) = master_with_file("liveness", 1);
+    for id in ["a", "b", "c"] {
+        master.receive_heartbeat(full_report(id, &[0], 1));
+    }
+    assert_eq!(read_locations(&master), vec![vec!["a", "b", "c"]]);
+
+    // Only b and c keep heartbeating.
+    std::thread::sleep(HEARTBEAT_INTERVAL * MAX_MISSED_HEARTBEATS + HEARTBEAT_INTERVAL / 2);
+    master.receive_heartbeat(heartbeat("b"));
+    master.receive_heartbeat(heartbeat("c"));
+    master.check_liveness();
+    assert_eq!(read_locations(&master), vec![vec!["b", "c"]]);
+    assert_eq!(master.get_free_chunkservers(1, 3).len(), 2);
+
+    // A chunkserver which comes back serves its replicas again.
+    master.receive_heartbeat(heartbeat("a"));
+    assert_eq!(read_locations(&master), vec![vec!["a", "b", "c"]]);
+}
+
+#[test]
+fn restarted_master_rebuilds_locations_from_full_reports() {
+    let (mut master, dir) = master_with_file("restart", 2);
+    master.receive_heartbeat(full_report("a", &[0, 1], 1));
+    master.receive_heartbeat(full_report("b", &[1], 1));
+    drop(master);
+
+    // Chunk locations aren't persisted, so the master asks for full reports.
+    let mut master = open_master(&dir);
+    assert!(matches!(master.get_read_infos("/f", 0, u64::MAX), Err(MasterError::ChunkNotFound)));
+    assert!(master.receive_heartbeat(heartbeat("a")).full_report_needed);
+    assert!(!master.receive_heartbeat(full_report("a", &[0, 1], 1)).full_report_needed);
+    master.receive_heartbeat(full_report("b", &[1], 1));
+    assert_eq!(read_locations(&master), vec![vec!["a"], vec!["a", "b"]]);
+
+    // A later full report replaces what the chunkserver was known to hold.
+    master.receive_heartbeat(full_report("a", &[1], 1));
+    assert!(matches!(master.get_read_infos("/f", 0, 1), Err(MasterError::ChunkNotFound)));
+    let read_info = master.get_read_infos("/f", CHUNK_SIZE_BYTES as u64, 1).unwrap();
+    assert_eq!(read_info.chunk_reads[0].locations, vec!["a", "b"]);
+}
+
+#[test]
+fn stale_replicas_are_rejected() {
+    let (mut master, dir) = master_with_file("stale", 1);
+    master.receive_heartbeat(full_report("a", &[0], 1));
+    master.receive_heartbeat(full_report("b", &[0], 1));
+    // c was told a new version of the chunk, but the master restarted before logging it.
+    let res = master.receive_heartbeat(Heartbeat { added_chunks: HashMap::from([(0, 2)]), ..full_report("c", &[], 1) });
+    assert!(res.delete_chunks.is_empty());
+    assert_eq!(read_locations(&master), vec![vec!["c"]]);
+
+    // a and b weren't told, so they are asked to report again, and their replicas are stale.
+    assert!(master.receive_heartbeat(heartbeat("a")).full_report_needed);
+    let res = master.receive_heartbeat(full_report("a", &[0], 1));
+    assert_eq!(res.delete_chunks, HashMap::from([(0, 1)]));
+
+    // The new version is logged, so it outlives a restart.
+    drop(master);
+    let mut master = open_master(&dir);
+    let res = master.receive_heartbeat(full_report("b", &[0], 1));
+    assert_eq!(res.delete_chunks, HashMap::from([(0, 1)]));
+    master.receive_heartbeat(Heartbeat { added_chunks: HashMap::from([(0, 2)]), ..full_report("c", &[], 1) });
+    assert_eq!(read_locations(&master), vec![vec!["c"]]);
+}
+
+#[test]
+fn versions_never_granted_are_not_adopted() {
+    let (mut master, dir) = master_with_file("ungranted", 1);
+    master.receive_heartbeat(full_report("a", &[0], 1));
+    // b is far ahead of any version granted, and c has a chunk which was never allocated.
+    let res = master.receive_heartbeat(Heartbeat { added_chunks: HashMap::from([(0, 1 << 40)]), ..full_report("b", &[], 1) });
+    assert!(res.delete_chunks.is_empty());
+    let res = master.receive_heartbeat(Heartbeat { added_chunks: HashMap::from([(5, 2)]), ..full_report("c", &[], 1) });
+    assert!(res.delete_chunks.is_empty());
+    assert_eq!(read_locations(&master), vec![vec!["a"]]);
+    assert!(!master.receive_heartbeat(heartbeat("a")).full_report_needed);
+
+    drop(master);
+    let mut master = open_master(&dir);
+    let res = master.receive_heartbeat(full_report("a", &[0], 1));
+    assert!(res.delete_chunks.is_empty());
+    assert_eq!(read_locations(&master), vec![vec!["a"]]);
+}
+
+#[test]
+fn snapshot_serializes_with_creates_under_it() {
+    let (master, _) = master_with_file("namespace-locks", 0);
+    let master = Arc::new(Mutex::new(master));
+    master.mkdir("/d").unwrap();
+
+    // While a snapshot of /d holds its locks, creating a file in /d waits...
+    let snapshot = MasterServer::lock_namespace(&master, &[], &["/d", "/copy"]);
+    let (done, created) = mpsc::channel();
+    let creator = {
+        let master = master.clone();
+        std::thread::spawn(move || {
+            master.mkdir("/d/x").unwrap();
+            done.send(()).unwrap();
+        })
+    };
+    // ...but operations on unrelated paths go ahead.
+    master.mkdir("/e").unwrap();
+    master.snapshot("/f", "/g").unwrap();
+    assert!(created.recv_timeout(Duration::from_millis(200)).is_err());
+    // Listing /d would wait too, so look at the master directly.
+    assert_eq!(master.lock().unwrap().ls("/d"), Vec::<String>::new());
+
+    drop(snapshot);
+    created.recv_timeout(Duration::from_secs(5)).unwrap();
+    creator.join().unwrap();
+    assert_eq!(master.ls("/d").unwrap(), vec!["/d/x/"]);
+
+    // Likewise, a snapshot of /d waits for a create in /d to finish.
+    let create = MasterServer::lock_namespace(&master, &[], &["/d/y"]);
+    let (done, snapshotted) = mpsc::channel();
+    let snapshotter = {
+        let master = master.clone();
+        std::thread::spawn(move || {
+            master.snapshot("/d", "/copy").unwrap();
+            done.send(()).unwrap();
+        })
+    };
+    assert!(snapshotted.recv_timeout(Duration::from_millis(200)).is_err());
+    drop(create);
+    snapshotted.recv_timeout(Duration::from_secs(5)).unwrap();
+    snapshotter.join().unwrap();
+    assert_eq!(master.ls("/copy").unwrap(), vec!["/copy/x/"]);
+}
+
+#[test]
+fn rmdir_and_ls_nested_directories() {
+    let (mut master, _) = master_with_file("rmdir", 0);
+    master.mkdir("/a/b/c").unwrap();
+    master.mkdir("/a/d").unwrap();
+    assert_eq!(master.ls("/"), vec!["/a/", "/f"]);
+    assert_eq!(master.ls("/a"), vec!["/a/b/", "/a/d/"]);
+    assert_eq!(master.ls("/a/b/"), vec!["/a/b/c/"]);
+    assert_eq!(master.ls("/a/b/c"), Vec::<String>::new());
+
+    assert!(matches!(master.rmdir("/a"), Err(MasterError::DirectoryNotEmpty)));
+    assert!(matches!(master.rmdir("/a/b"), Err(MasterError::DirectoryNotEmpty)));
+    assert!(matches!(master.rmdir("/f"), Err(MasterError::InvalidPath)));
+    assert!(matches!(master.rmdir("/"), Err(MasterError::InvalidPath)));
+    assert!(matches!(master.rmdir("/a/x"), Err(MasterError::FileNotFound)));
+
+    master.rmdir("/a/b/c").unwrap();
+    master.rmdir("/a/b").unwrap();
+    assert_eq!(master.ls("/a"), vec!["/a/d/"]);
+    assert_eq!(master.ls_tree("/"), vec!["/", "/a/", "/a/d/", "/f"]);
+}
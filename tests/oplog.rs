@@ -0,0 +1,153 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use gfs::common::NetworkShim;
+use gfs::master::{write_checkpoint, MasterServer};
+use gfs::oplog::{LogEntry, LogRecord, OperationLog};
+
+/// An empty directory for a test's log.
+fn log_dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("oplog").join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn open_master(dir: &Path) -> MasterServer {
+    MasterServer::open(Arc::new(Mutex::new(NetworkShim::new())), dir.to_path_buf()).unwrap()
+}
+
+fn mkdir(seq: u64, path: &str) -> LogEntry {
+    LogEntry { seq, record: LogRecord::Mkdir { path: path.to_string() } }
+}
+
+fn segment_files(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir).unwrap()
+        .map(|file| file.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("oplog."))
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn replays_entries_across_segments() {
+    let dir = log_dir("replay");
+    let (mut oplog, entries) = OperationLog::open(dir.clone()).unwrap();
+    assert!(entries.is_empty());
+    oplog.append(&[mkdir(1, "/a")]).unwrap();
+    assert_eq!(oplog.roll().unwrap(), Some(0));
+    assert_eq!(oplog.roll().unwrap(), None);
+    oplog.append(&[mkdir(2, "/b"), mkdir(3, "/c")]).unwrap();
+    drop(oplog);
+
+    let (_, entries) = OperationLog::open(dir.clone()).unwrap();
+    assert_eq!(entries.iter().map(|entry| entry.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+    assert_eq!(segment_files(&dir), vec!["oplog.00000000", "oplog.00000001"]);
+}
+
+#[test]
+fn discards_torn_tail() {
+    let dir = log_dir("torn");
+    let (mut oplog, _) = OperationLog::open(dir.clone()).unwrap();
+    oplog.append(&[mkdir(1, "/a")]).unwrap();
+    drop(oplog);
+    let path = dir.join("oplog.00000000");
+    let mut data = std::fs::read(&path).unwrap();
+    data.extend(b"{\"seq\":2,\"rec");
+    std::fs::write(&path, &data).unwrap();
+
+    let (mut oplog, entries) = OperationLog::open(dir.clone()).unwrap();
+    assert_eq!(entries.len(), 1);
+    oplog.append(&[mkdir(2, "/b")]).unwrap();
+    drop(oplog);
+    let (_, entries) = OperationLog::open(dir).unwrap();
+    assert_eq!(entries.iter().map(|entry| entry.seq).collect::<Vec<_>>(), vec![1, 2]);
+}
+
+#[test]
+fn discards_tail_torn_within_a_character() {
+    let dir = log_dir("torn-utf8");
+    let (mut oplog, _) = OperationLog::open(dir.clone()).unwrap();
+    oplog.append(&[mkdir(1, "/caf\u{e9}")]).unwrap();
+    drop(oplog);
+    let path = dir.join("oplog.00000000");
+    let mut data = std::fs::read(&path).unwrap();
+    data.extend(b"{\"seq\":2,\"record\":{\"Mkdir\":{\"path\":\"/caf\xc3");
+    std::fs::write(&path, &data).unwrap();
+
+    let (_, entries) = OperationLog::open(dir.clone()).unwrap();
+    assert_eq!(entries.len(), 1);
+    let master = open_master(&dir);
+    assert_eq!(master.ls_tree("/"), vec!["/", "/caf\u{e9}/"]);
+}
+
+#[test]
+fn master_replays_log() {
+    let dir = log_dir("master-replay");
+    let mut master = open_master(&dir);
+    master.mkdir("/a/b").unwrap();
+    master.rename("/a/b", "/c", false).unwrap();
+    drop(master);
+
+    let master = open_master(&dir);
+    assert_eq!(master.ls_tree("/"), vec!["/", "/a/", "/c/"]);
+}
+
+#[test]
+fn master_recovers_from_checkpoint_and_log() {
+    let dir = log_dir("master-checkpoint");
+    let mut master = open_master(&dir);
+    master.mkdir("/a").unwrap();
+    master.mkdir("/b").unwrap();
+    let (log_dir, sealed) = master.begin_checkpoint().unwrap();
+    master.mkdir("/c").unwrap();
+    write_checkpoint(&log_dir, sealed).unwrap();
+    assert_eq!(segment_files(&dir), vec!["oplog.00000001"]);
+    master.rmdir("/a").unwrap();
+    drop(master);
+
+    let master = open_master(&dir);
+    assert_eq!(master.ls_tree("/"), vec!["/", "/b/", "/c/"]);
+}
+
+#[test]
+fn checkpoint_skips_entries_already_folded_in() {
+    let dir = log_dir("master-refold");
+    let mut master = open_master(&dir);
+    master.mkdir("/a").unwrap();
+    let (log_dir, sealed) = master.begin_checkpoint().unwrap();
+    drop(master);
+    // Crash after writing the checkpoint but before removing the sealed segment.
+    let segment = std::fs::read(dir.join("oplog.00000000")).unwrap();
+    write_checkpoint(&log_dir, sealed).unwrap();
+    std::fs::write(dir.join("oplog.00000000"), segment).unwrap();
+
+    let mut master = open_master(&dir);
+    master.rename("/a", "/b", false).unwrap();
+    drop(master);
+    let master = open_master(&dir);
+    assert_eq!(master.ls_tree("/"), vec!["/", "/b/"]);
+}
+
+#[test]
+fn overwriting_rename_is_one_record() {
+    let dir = log_dir("rename-overwrite");
+    let (mut oplog, _) = OperationLog::open(dir.clone()).unwrap();
+    let create = |seq, path: &str| LogEntry { seq, record: LogRecord::CreateFile { path: path.to_string() } };
+    oplog.append(&[create(1, "/a"), create(2, "/b")]).unwrap();
+    drop(oplog);
+
+    let mut master = open_master(&dir);
+    master.rename("/a", "/b", true).unwrap();
+    drop(master);
+    let (_, entries) = OperationLog::open(dir.clone()).unwrap();
+    assert_eq!(entries.len(), 3);
+    assert!(matches!(&entries[2].record, LogRecord::Rename { replaced: Some(_), .. }));
+
+    let mut master = open_master(&dir);
+    assert_eq!(master.ls_tree("/"), vec!["/", "/b"]);
+    // The replaced file was deleted, so it can be restored once the name is free.
+    master.rename("/b", "/c", false).unwrap();
+    master.undelete("/b").unwrap();
+    assert_eq!(master.ls_tree("/"), vec!["/", "/b", "/c"]);
+}
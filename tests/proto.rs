@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use protobuf::{EnumOrUnknown, Message};
+use gfs::master::{Heartbeat, HeartbeatResponse, MasterError};
+use gfs::proto::gfs as pb;
+
+/// Encode and decode a message, as when it's sent over the network.
+fn round_trip<M: Message>(msg: &M) -> M {
+    M::parse_from_bytes(&msg.write_to_bytes().unwrap()).unwrap()
+}
+
+#[test]
+fn heartbeat_round_trips() {
+    let heartbeat = Heartbeat {
+        chunkserver_id: "cs".to_string(),
+        disk_used: 1,
+        disk_free: 2,
+        full_report: true,
+        added_chunks: HashMap::from([(1, 3), (2, 0), (7, 1)]),
+        removed_chunks: vec![4],
+        corrupt_chunks: vec![5],
+    };
+    let decoded: Heartbeat = round_trip(&pb::Heartbeat::from(&heartbeat)).into();
+    assert_eq!(decoded.added_chunks, heartbeat.added_chunks);
+    assert_eq!(decoded.removed_chunks, heartbeat.removed_chunks);
+    assert_eq!(decoded.corrupt_chunks, heartbeat.corrupt_chunks);
+
+    let res = HeartbeatResponse { full_report_needed: false, delete_chunks: HashMap::from([(1, 3), (9, 0)]) };
+    let decoded: HeartbeatResponse = round_trip(&pb::HeartbeatResponse::from(&res)).into();
+    assert_eq!(decoded.delete_chunks, res.delete_chunks);
+}
+
+#[test]
+fn unknown_master_errors_are_unavailable() {
+    let mut msg = pb::MasterResult::new();
+    msg.error = EnumOrUnknown::from_i32(1000);
+    let res: Result<(), MasterError> = round_trip(&msg).into();
+    assert!(matches!(res, Err(MasterError::Unavailable)));
+
+    msg.error = pb::MasterError::MASTER_ERROR_FILE_EXISTS.into();
+    let res: Result<(), MasterError> = round_trip(&msg).into();
+    assert!(matches!(res, Err(MasterError::FileExists)));
+}
+
+#[test]
+fn append_failures_round_trip() {
+    let msg = pb::MasterResult::from(&Err(MasterError::AppendFailed));
+    let res: Result<(), MasterError> = round_trip(&msg).into();
+    assert!(matches!(res, Err(MasterError::AppendFailed)));
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use gfs::chunk::CHUNK_SIZE_BYTES;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use gfs::chunkserver::{block_checksums, spawn_scrubber, ChunkStore, Chunkserver, ChunkserverError, ChunkserverStorage};
+use gfs::common::{sha256sum, NetworkShim};
+use gfs::master::{MasterServer, MasterServerState};
+use gfs::packed::PackedChunkStorage;
+
+/// An empty directory for a test to store chunks in.
+fn storage_dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("storage").join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn chunk_data(seed: u8) -> Vec<u8> {
+    (0..CHUNK_SIZE_BYTES).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+}
+
+#[test]
+fn loads_chunks_stored_before_versioning() {
+    let dir = storage_dir("legacy");
+    std::fs::write(dir.join("ch7"), chunk_data(7)).unwrap();
+
+    let storage = ChunkserverStorage::new(dir.clone());
+    assert_eq!(storage.chunk_version(7), Some(0));
+    assert_eq!(storage.read_chunk(7).unwrap(), chunk_data(7));
+    assert!(!dir.join("ch7").exists());
+
+    // The chunk can be loaded again once renamed.
+    let storage = ChunkserverStorage::new(dir);
+    assert_eq!(storage.read_chunk(7).unwrap(), chunk_data(7));
+}
+
+#[test]
+fn cleans_up_interrupted_writes() {
+    let dir = storage_dir("interrupted");
+    let mut storage = ChunkserverStorage::new(dir.clone());
+    storage.write_chunk(1, 1, &chunk_data(1)).unwrap();
+    storage.write_chunk(2, 1, &chunk_data(2)).unwrap();
+    drop(storage);
+
+    // A crash while writing chunk 3 left temporary files, and one while replacing chunk 2 left
+    // the new version's checksums. A copy of chunk 4 from before writes were atomic is short.
+    std::fs::write(dir.join("ch3.1.crc.tmp"), [0; 16]).unwrap();
+    std::fs::write(dir.join("ch3.1.tmp"), &chunk_data(3)[..100]).unwrap();
+    std::fs::copy(dir.join("ch2.1.crc"), dir.join("ch2.2.crc")).unwrap();
+    std::fs::write(dir.join("ch4.1"), &chunk_data(4)[..100]).unwrap();
+
+    let storage = ChunkserverStorage::new(dir.clone());
+    assert_eq!(storage.chunk_versions(), HashMap::from([(1, 1), (2, 1)]));
+    assert_eq!(storage.read_chunk(2).unwrap(), chunk_data(2));
+    let mut files: Vec<String> = std::fs::read_dir(&dir).unwrap()
+        .map(|file| file.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(files, vec!["ch1.1", "ch1.1.crc", "ch2.1", "ch2.1.crc"]);
+}
+
+#[test]
+fn scrubs_without_rate_limit() {
+    let dir = storage_dir("scrub-unlimited");
+    let mut storage = ChunkserverStorage::new(dir.clone());
+    for i in 0..3 {
+        storage.write_chunk(i, 1, &chunk_data(i as u8)).unwrap();
+    }
+    drop(storage);
+    flip_byte(&dir.join("ch1.1"), 10);
+
+    let network = Arc::new(Mutex::new(NetworkShim::new()));
+    let master = Arc::new(Mutex::new(MasterServer::new(network.clone(), MasterServerState::new())));
+    let storage = Box::new(ChunkserverStorage::new(dir));
+    let chunkserver = Arc::new(Mutex::new(Chunkserver::new(master, network, "cs".to_string(), 1 << 20, storage)));
+    spawn_scrubber(chunkserver.clone(), 0, Duration::from_secs(3600));
+
+    let start = Instant::now();
+    while chunkserver.lock().unwrap().scrub_progress().passes == 0 {
+        assert!(start.elapsed() < Duration::from_secs(10), "scrub pass never finished");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let progress = chunkserver.lock().unwrap().scrub_progress();
+    assert_eq!(progress.chunks_scrubbed, 3);
+    assert_eq!(progress.corrupt_chunks, 1);
+}
+
+#[test]
+fn unreadable_replicas_are_kept() {
+    let dir = storage_dir("unreadable");
+    let mut storage = ChunkserverStorage::new(dir.join("files"));
+    storage.write_chunk(1, 1, &chunk_data(1)).unwrap();
+    let mut packed = PackedChunkStorage::open(dir.join("packed"));
+    packed.write_chunk(1, 1, &chunk_data(1)).unwrap();
+
+    let network = Arc::new(Mutex::new(NetworkShim::new()));
+    let master = Arc::new(Mutex::new(MasterServer::new(network.clone(), MasterServerState::new())));
+    let stores: Vec<(Box<dyn ChunkStore>, PathBuf)> = vec![
+        (Box::new(storage), dir.join("files").join("ch1.1")),
+        (Box::new(packed), dir.join("packed").join("seg.00000000")),
+    ];
+    for (storage, path) in stores {
+        let chunkserver = Arc::new(Mutex::new(Chunkserver::new(master.clone(), network.clone(), "cs".to_string(), 1 << 20, storage)));
+
+        // A failed read isn't a checksum mismatch, so neither reads nor scrubbing drop the replica.
+        let moved = path.with_extension("moved");
+        std::fs::rename(&path, &moved).unwrap();
+        let res = chunkserver.lock().unwrap().read_chunk(1);
+        assert!(matches!(res, Err(ChunkserverError::StorageFailed)));
+        spawn_scrubber(chunkserver.clone(), 0, Duration::from_secs(3600));
+        let start = Instant::now();
+        while chunkserver.lock().unwrap().scrub_progress().passes == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10), "scrub pass never finished");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let progress = chunkserver.lock().unwrap().scrub_progress();
+        assert_eq!((progress.corrupt_chunks, progress.read_errors), (0, 1));
+
+        std::fs::rename(&moved, &path).unwrap();
+        assert_eq!(chunkserver.lock().unwrap().read_chunk(1).unwrap(), chunk_data(1));
+    }
+}
+
+#[test]
+fn failed_commit_keeps_pushed_data() {
+    let dir = storage_dir("commit-fails");
+    let network = Arc::new(Mutex::new(NetworkShim::new()));
+    let master = Arc::new(Mutex::new(MasterServer::new(network.clone(), MasterServerState::new())));
+    let storage = Box::new(ChunkserverStorage::new(dir.clone()));
+    let mut chunkserver = Chunkserver::new(master, network, "cs".to_string(), 1 << 20, storage);
+    let data = chunk_data(1);
+    chunkserver.push_chunk(&data).unwrap();
+
+    // The chunk can't be written once its directory is gone.
+    std::fs::remove_dir_all(&dir).unwrap();
+    let res = chunkserver.commit_chunk(sha256sum(&data), 1, 1);
+    assert!(matches!(res, Err(ChunkserverError::StorageFailed)));
+
+    std::fs::create_dir_all(&dir).unwrap();
+    chunkserver.commit_chunk(sha256sum(&data), 1, 1).unwrap();
+    assert_eq!(chunkserver.read_chunk(1).unwrap(), data);
+}
+
+#[test]
+fn heartbeat_reports_free_disk_space() {
+    let dir = storage_dir("disk-free");
+    let network = Arc::new(Mutex::new(NetworkShim::new()));
+    let master = Arc::new(Mutex::new(MasterServer::new(network.clone(), MasterServerState::new())));
+    let storage = Box::new(ChunkserverStorage::new(dir.clone()));
+    let available = storage.disk_available().unwrap();
+    assert!(available > 0);
+
+    // An allocation larger than the disk is capped at the space left on it.
+    let mut chunkserver = Chunkserver::new(master.clone(), network.clone(), "cs".to_string(), u64::MAX, storage);
+    let heartbeat = chunkserver.heartbeat();
+    assert!(heartbeat.disk_free > 0 && heartbeat.disk_free < u64::MAX);
+
+    let storage = Box::new(ChunkserverStorage::new(dir));
+    let mut chunkserver = Chunkserver::new(master, network, "cs".to_string(), 1 << 10, storage);
+    assert_eq!(chunkserver.heartbeat().disk_free, (1 << 10).min(available));
+}
+
+//
+// Packed storage.
+//
+
+/// The segment files in a packed storage directory, in order.
+fn segments(dir: &Path) -> Vec<PathBuf> {
+    let mut segments: Vec<PathBuf> = std::fs::read_dir(dir).unwrap()
+        .map(|x| x.unwrap().path())
+        .filter(|path| path.extension().is_none_or(|x| x != "idx" && x != "damaged"))
+        .collect();
+    segments.sort();
+    segments
+}
+
+/// Flip a byte of a file.
+fn flip_byte(path: &Path, offset: usize) {
+    let mut bytes = std::fs::read(path).unwrap();
+    bytes[offset] ^= 0xff;
+    std::fs::write(path, bytes).unwrap();
+}
+
+#[test]
+fn packed_skips_damaged_header_in_sealed_segment() {
+    let dir = storage_dir("packed-damaged-sealed");
+    let mut storage = PackedChunkStorage::open(dir.clone());
+    for i in 0..1100 {
+        storage.write_chunk(i, 1, &chunk_data(i as u8)).unwrap();
+    }
+    drop(storage);
+    let sealed = segments(&dir)[0].clone();
+    let len = std::fs::metadata(&sealed).unwrap().len();
+    flip_byte(&sealed, 0);
+
+    let storage = PackedChunkStorage::open(dir.clone());
+    assert_eq!(storage.chunk_version(0), None);
+    assert_eq!(storage.chunk_versions().len(), 1099);
+    for i in 1..1100 {
+        assert_eq!(storage.read_chunk(i).unwrap(), chunk_data(i as u8));
+    }
+    assert_eq!(std::fs::metadata(&sealed).unwrap().len(), len);
+}
+
+#[test]
+fn packed_quarantines_last_segment_after_damaged_header() {
+    let dir = storage_dir("packed-damaged-last");
+    let mut storage = PackedChunkStorage::open(dir.clone());
+    for i in 0..10 {
+        storage.write_chunk(i, 1, &chunk_data(i as u8)).unwrap();
+    }
+    drop(storage);
+    let segment = segments(&dir)[0].clone();
+    let record_len = std::fs::metadata(&segment).unwrap().len() / 10;
+    flip_byte(&segment, 5 * record_len as usize);
+    let damaged = std::fs::read(&segment).unwrap().split_off(5 * record_len as usize);
+
+    // The last segment isn't indexed yet, so the records after the damaged one can't be found,
+    // but they are moved aside rather than lost.
+    let mut storage = PackedChunkStorage::open(dir.clone());
+    assert_eq!(storage.chunk_versions().len(), 5);
+    assert_eq!(std::fs::metadata(&segment).unwrap().len(), 5 * record_len);
+    let quarantined = dir.join(format!("seg.00000000.{}.damaged", 5 * record_len));
+    assert_eq!(std::fs::read(quarantined).unwrap(), damaged);
+    storage.write_chunk(5, 2, &chunk_data(5)).unwrap();
+    drop(storage);
+    let storage = PackedChunkStorage::open(dir);
+    assert_eq!(storage.chunk_versions().len(), 6);
+    assert_eq!(storage.read_chunk(5).unwrap(), chunk_data(5));
+}
+
+/// A record of a chunk, as the packed storage writes it.
+fn forged_record(chunk_id: u64, data: &[u8]) -> Vec<u8> {
+    let checksums = block_checksums(data);
+    let mut record = vec![];
+    record.extend_from_slice(&chunk_id.to_le_bytes());
+    record.extend_from_slice(&1u64.to_le_bytes());
+    record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(checksums.len() as u32).to_le_bytes());
+    checksums.iter().for_each(|x| record.extend_from_slice(&x.to_le_bytes()));
+    let header_checksum = crc32fast::hash(&record);
+    record.extend_from_slice(&header_checksum.to_le_bytes());
+    record.extend_from_slice(data);
+    record
+}
+
+#[test]
+fn packed_ignores_records_forged_in_chunk_data() {
+    let dir = storage_dir("packed-forged");
+    let mut storage = PackedChunkStorage::open(dir.clone());
+    let mut data = vec![0u8; CHUNK_SIZE_BYTES];
+    let forged = forged_record(5000, b"forged");
+    data[100..100 + forged.len()].copy_from_slice(&forged);
+    storage.write_chunk(0, 1, &data).unwrap();
+    for i in 1..1100 {
+        storage.write_chunk(i, 1, &chunk_data(i as u8)).unwrap();
+    }
+    drop(storage);
+    flip_byte(&segments(&dir)[0], 0);
+
+    let storage = PackedChunkStorage::open(dir.clone());
+    assert_eq!(storage.chunk_version(5000), None);
+    assert_eq!(storage.chunk_version(0), None);
+    assert_eq!(storage.chunk_versions().len(), 1099);
+}
+
+#[test]
+fn packed_discards_torn_record() {
+    let dir = storage_dir("packed-torn");
+    let mut storage = PackedChunkStorage::open(dir.clone());
+    for i in 0..10 {
+        storage.write_chunk(i, 1, &chunk_data(i as u8)).unwrap();
+    }
+    drop(storage);
+    let segment = segments(&dir)[0].clone();
+    let len = std::fs::metadata(&segment).unwrap().len();
+    std::fs::OpenOptions::new().write(true).open(&segment).unwrap().set_len(len - 100).unwrap();
+
+    let mut storage = PackedChunkStorage::open(dir.clone());
+    assert_eq!(storage.chunk_versions().len(), 9);
+    assert_eq!(storage.chunk_version(9), None);
+
+    // Appends continue after the last intact record.
+    storage.write_chunk(9, 2, &chunk_data(9)).unwrap();
+    drop(storage);
+    let storage = PackedChunkStorage::open(dir);
+    assert_eq!(storage.chunk_versions().len(), 10);
+    assert_eq!(storage.read_chunk(9).unwrap(), chunk_data(9));
+}
+
+#[test]
+fn packed_compacts_and_reopens() {
+    let dir = storage_dir("packed-compact");
+    let mut storage = PackedChunkStorage::open(dir.clone());
+    for i in 0..1100 {
+        storage.write_chunk(i, 1, &chunk_data(i as u8)).unwrap();
+    }
+    let first = segments(&dir)[0].clone();
+
+    // Once half of the first segment is deleted, the rest of it is moved out.
+    let mut deleted = 0;
+    while first.exists() {
+        assert!(storage.delete_chunk(deleted));
+        deleted += 1;
+    }
+    assert!(deleted > 400 && deleted < 600);
+    storage.write_chunk(1000, 2, &chunk_data(0)).unwrap();
+    let expected: HashMap<u64, u64> = (deleted..1100).map(|i| (i, if i == 1000 { 2 } else { 1 })).collect();
+    assert_eq!(storage.chunk_versions(), expected);
+    drop(storage);
+
+    let storage = PackedChunkStorage::open(dir.clone());
+    assert_eq!(storage.chunk_versions(), expected);
+    for i in deleted..1100 {
+        assert_eq!(storage.read_chunk(i).unwrap(), chunk_data(if i == 1000 { 0 } else { i as u8 }));
+    }
+}
+
+#[test]
+fn packed_deletes_outlive_restarts() {
+    let dir = storage_dir("packed-deletes");
+    let mut storage = PackedChunkStorage::open(dir.clone());
+    for i in 0..1100 {
+        storage.write_chunk(i, 1, &chunk_data(i as u8)).unwrap();
+    }
+    // Chunk 0 is in the first segment, and chunk 1099 in the second.
+    assert!(storage.delete_chunk(0));
+    assert!(storage.delete_chunk(1099));
+    assert!(storage.delete_chunk(5));
+    storage.write_chunk(5, 2, &chunk_data(5)).unwrap();
+    drop(storage);
+
+    let mut storage = PackedChunkStorage::open(dir.clone());
+    assert_eq!((storage.chunk_version(0), storage.chunk_version(1099), storage.chunk_version(5)), (None, None, Some(2)));
+    assert_eq!(storage.chunk_versions().len(), 1098);
+
+    // Compacting the second segment moves chunk 0's tombstone, as the first still holds its record.
+    for i in 1100..2100 {
+        storage.write_chunk(i, 1, &chunk_data(i as u8)).unwrap();
+    }
+    let second = segments(&dir)[1].clone();
+    let mut deleted = 1100;
+    while second.exists() {
+        storage.delete_chunk(deleted);
+        deleted += 1;
+    }
+    assert!(segments(&dir)[0].ends_with("seg.00000000"));
+    drop(storage);
+
+    let storage = PackedChunkStorage::open(dir);
+    assert_eq!((storage.chunk_version(0), storage.chunk_version(1099), storage.chunk_version(5)), (None, None, Some(2)));
+    assert_eq!(storage.chunk_versions().len(), 1098 + 2100 - deleted as usize);
+}